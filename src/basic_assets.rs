@@ -1,3 +1,5 @@
+use crate::material::{load_fluid_materials, FluidMaterials, MaterialId};
+use crate::thermal::Temperature;
 use bevy::prelude::*;
 use bevy::utils::HashMap;
 
@@ -11,18 +13,55 @@ pub struct MeshShapeDatabase {
     pub handles: HashMap<SimAssetId, Handle<Mesh>>,
 }
 
+/// Render material of every fluid material, keyed like `MaterialId`
 #[derive(Resource, Default)]
 pub struct MaterialColorDatabase {
     pub handles: HashMap<usize, Handle<ColorMaterial>>,
 }
 
+/// What the particle colors show
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParticleColoring {
+    #[default]
+    Material,
+    /// Blue for cold through red for hot, see [`TemperaturePalette`]
+    Temperature,
+}
+
+impl ParticleColoring {
+    pub const ALL: [ParticleColoring; 2] =
+        [ParticleColoring::Material, ParticleColoring::Temperature];
+}
+
+/// Render materials for a range of temperatures, so particles can share them
+#[derive(Resource, Default)]
+pub struct TemperaturePalette {
+    pub handles: Vec<Handle<ColorMaterial>>,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl TemperaturePalette {
+    pub fn get(&self, temperature: f32) -> Option<&Handle<ColorMaterial>> {
+        let steps = self.handles.len().checked_sub(1)?;
+        let t = ((temperature - self.min) / (self.max - self.min)).clamp(0.0, 1.0);
+        self.handles.get((t * steps as f32).round() as usize)
+    }
+}
+
 pub struct ParticleAssetPlugin;
 
 impl Plugin for ParticleAssetPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(MeshShapeDatabase::default())
             .insert_resource(MaterialColorDatabase::default())
-            .add_systems(PreStartup, load_particle_visuals);
+            .init_resource::<TemperaturePalette>()
+            .init_resource::<ParticleColoring>()
+            .add_systems(
+                PreStartup,
+                load_particle_visuals.after(load_fluid_materials),
+            )
+            .add_systems(Update, update_particle_colors);
     }
 }
 
@@ -31,19 +70,51 @@ fn load_particle_visuals(
     mut materials: ResMut<Assets<ColorMaterial>>,
     mut mesh_db: ResMut<MeshShapeDatabase>,
     mut color_db: ResMut<MaterialColorDatabase>,
+    mut palette: ResMut<TemperaturePalette>,
+    fluid_materials: Res<FluidMaterials>,
 ) {
     let circle = Circle::new(0.2);
     let mesh_handle = meshes.add(circle);
     mesh_db.handles.insert(SimAssetId::Particle, mesh_handle);
 
-    let color_1 = ColorMaterial::from_color(Color::Srgba(Srgba::rgba_u8(100, 100, 255, 20)));
-    let color_2 = ColorMaterial::from_color(Color::Srgba(Srgba::rgba_u8(255, 100, 100, 20)));
-    let color_3 = ColorMaterial::from_color(Color::Srgba(Srgba::rgba_u8(100, 255, 100, 20)));
-    let color_handle = materials.add(color_1);
-    color_db.handles.insert(0, color_handle);
-    let color_handle = materials.add(color_2);
-    color_db.handles.insert(1, color_handle);
-    let color_handle = materials.add(color_3);
-    color_db.handles.insert(2, color_handle);
+    for (&id, fluid) in fluid_materials.materials.iter() {
+        let color_handle = materials.add(ColorMaterial::from_color(fluid.color));
+        color_db.handles.insert(id, color_handle);
+    }
+
+    const PALETTE_STEPS: usize = 16;
+    palette.min = 0.0;
+    palette.max = 100.0;
+    palette.handles = (0..PALETTE_STEPS)
+        .map(|step| {
+            let t = step as f32 / (PALETTE_STEPS - 1) as f32;
+            let color = Color::srgba(t, 0.3, 1.0 - t, 0.5);
+            materials.add(ColorMaterial::from_color(color))
+        })
+        .collect();
     println!("Loaded particle Assets");
 }
+
+/// Swaps the render material of every particle whose color changed
+fn update_particle_colors(
+    mut particles: Query<(
+        &mut MeshMaterial2d<ColorMaterial>,
+        &MaterialId,
+        &Temperature,
+    )>,
+    coloring: Res<ParticleColoring>,
+    color_db: Res<MaterialColorDatabase>,
+    palette: Res<TemperaturePalette>,
+) {
+    for (mut mesh_material, material, temperature) in particles.iter_mut() {
+        let handle = match *coloring {
+            ParticleColoring::Material => color_db.handles.get(&material.0),
+            ParticleColoring::Temperature => palette.get(temperature.0),
+        };
+        if let Some(handle) = handle {
+            if mesh_material.0 != *handle {
+                mesh_material.0 = handle.clone();
+            }
+        }
+    }
+}
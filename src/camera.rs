@@ -1,4 +1,3 @@
-use bevy::color::palettes::tailwind::BLUE_950;
 use bevy::prelude::*;
 use bevy_pancam::{PanCam, PanCamPlugin};
 
@@ -1,4 +1,8 @@
-use bevy::{prelude::*, utils::HashSet};
+use bevy::{
+    ecs::{component::ComponentId, world::DeferredWorld},
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
 use std::array;
 
 pub const CHUNK_SIZE: usize = 64;
@@ -7,26 +11,103 @@ pub struct Chunk<T> {
     pub cells: [T; CHUNK_SIZE * CHUNK_SIZE],
 }
 
+/// A single loaded chunk of the lookup map together with the number of entities it holds, so
+/// empty chunks can be unloaded without scanning every cell.
+pub struct EntityChunk {
+    pub chunk: Chunk<HashSet<Entity>>,
+    pub population: usize,
+}
+
+/// Unbounded spatial registry of particle entities.
+/// Chunks are allocated on demand when the first entity enters them and dropped again once the
+/// last one leaves.
 #[derive(Resource)]
-pub struct EntityLookupChunk(pub Chunk<HashSet<Entity>>);
+pub struct EntityLookupChunk {
+    chunks: HashMap<IVec2, Box<EntityChunk>>,
+    /// World size of a single cell. It matches the smoothing radius, so the 3x3 neighborhood of a
+    /// cell always covers the kernel support.
+    cell_size: f32,
+}
+
+impl Default for EntityLookupChunk {
+    fn default() -> Self {
+        Self::new(1.0)
+    }
+}
 
-#[derive(Component, Default, Debug, Clone)]
+/// Position of an entity in the lookup map: the chunk coordinate and the cell inside that chunk.
+/// Inserting the component registers the entity in the [`EntityLookupChunk`] resource, and
+/// removing it or despawning the entity unregisters it again. Systems moving an entity between
+/// cells by mutating the component bypass the hooks and have to use
+/// [`EntityLookupChunk::relocate`].
+#[derive(Component, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[component(on_insert = register_chunk_position, on_replace = unregister_chunk_position)]
 pub struct ChunkPosition {
-    pub pos: UVec2,
+    pub chunk: IVec2,
+    pub cell: UVec2,
 }
 
-impl<T> Chunk<T> {
-    pub fn get_chunk_pos(x: f32, y: f32) -> Option<UVec2> {
-        let pos =
-            if x > -0.5 && x < CHUNK_SIZE as f32 + 0.5 && y > -0.5 && y < CHUNK_SIZE as f32 + 0.5 {
-                UVec2::new(x.round() as u32, y.round() as u32)
-            } else {
-                return None;
-            };
+fn register_chunk_position(mut world: DeferredWorld, entity: Entity, _: ComponentId) {
+    let Some(pos) = world.get::<ChunkPosition>(entity).copied() else {
+        return;
+    };
+    if let Some(mut lookup) = world.get_resource_mut::<EntityLookupChunk>() {
+        lookup.insert(entity, &pos);
+    }
+}
+
+fn unregister_chunk_position(mut world: DeferredWorld, entity: Entity, _: ComponentId) {
+    let Some(pos) = world.get::<ChunkPosition>(entity).copied() else {
+        return;
+    };
+    if let Some(mut lookup) = world.get_resource_mut::<EntityLookupChunk>() {
+        lookup.remove(entity, &pos);
+    }
+}
+
+/// Disagreement between the registry and the `ChunkPosition` components, see
+/// [`EntityLookupChunk::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryMismatch {
+    /// Registered in a cell, but no entity with that position exists
+    Dangling { entity: Entity, pos: ChunkPosition },
+    /// The cell of the entity's `ChunkPosition` does not hold it
+    Missing { entity: Entity, pos: ChunkPosition },
+    /// The population counter of a chunk disagrees with its cells
+    Population {
+        chunk: IVec2,
+        counted: usize,
+        actual: usize,
+    },
+}
+
+impl ChunkPosition {
+    /// Cells are centered on multiples of `cell_size`, so cell `i` covers
+    /// `[(i - 0.5) * cell_size, (i + 0.5) * cell_size)`.
+    pub fn from_world(x: f32, y: f32, cell_size: f32) -> Self {
+        Self::from_global_cell(IVec2::new(
+            (x / cell_size).round() as i32,
+            (y / cell_size).round() as i32,
+        ))
+    }
+
+    pub fn from_global_cell(global: IVec2) -> Self {
+        let size = CHUNK_SIZE as i32;
+        let chunk = IVec2::new(global.x.div_euclid(size), global.y.div_euclid(size));
+        let cell = UVec2::new(
+            global.x.rem_euclid(size) as u32,
+            global.y.rem_euclid(size) as u32,
+        );
+        Self { chunk, cell }
+    }
 
-        Some(pos)
+    /// The cell coordinate across all chunks
+    pub fn global_cell(&self) -> IVec2 {
+        self.chunk * CHUNK_SIZE as i32 + self.cell.as_ivec2()
     }
+}
 
+impl<T> Chunk<T> {
     pub fn index(x: usize, y: usize) -> Option<usize> {
         if !Self::is_valid_pos(x, y) {
             return None;
@@ -43,125 +124,280 @@ impl<T> Chunk<T> {
     }
 
     pub fn is_valid_pos(x: usize, y: usize) -> bool {
-        if x >= CHUNK_SIZE || y >= CHUNK_SIZE {
-            false
-        } else {
-            true
+        x < CHUNK_SIZE && y < CHUNK_SIZE
+    }
+}
+
+impl<T: Default> Default for Chunk<T> {
+    fn default() -> Self {
+        Self {
+            cells: array::from_fn(|_| T::default()),
+        }
+    }
+}
+
+impl EntityLookupChunk {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            chunks: HashMap::default(),
+            cell_size,
         }
     }
 
-    pub fn get_neighbors(&self, x: usize, y: usize) -> [Option<&T>; 8] {
-        let mut elements = [
-            (-1, -1),
-            (-1, 0),
-            (-1, 1),
-            (0, -1),
-            (0, 1),
-            (1, -1),
-            (1, 0),
-            (1, 1),
-        ]
-        .into_iter()
-        .map(|(dx, dy)| {
-            let x_i = x as i32;
-            let y_i = y as i32;
-            if dx == -1 && x == 0 {
-                return None;
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    /// Drops every registered entity and switches to a new cell size. Callers have to re-insert
+    /// their entities with positions computed by [`Self::chunk_position`] afterwards.
+    pub fn reset(&mut self, cell_size: f32) {
+        self.chunks.clear();
+        self.cell_size = cell_size;
+    }
+
+    /// Position of the cell containing the world point `(x, y)`
+    pub fn chunk_position(&self, x: f32, y: f32) -> ChunkPosition {
+        ChunkPosition::from_world(x, y, self.cell_size)
+    }
+
+    pub fn insert(&mut self, element: Entity, pos: &ChunkPosition) {
+        let entry = self.chunks.entry(pos.chunk).or_insert_with(|| {
+            Box::new(EntityChunk {
+                chunk: Chunk::default(),
+                population: 0,
+            })
+        });
+        let cell = entry
+            .chunk
+            .get_mut(pos.cell.x as usize, pos.cell.y as usize)
+            .expect("Cell positions are always inside their chunk");
+        if cell.insert(element) {
+            entry.population += 1;
+        }
+    }
+
+    /// Moves the entity to the cell containing the world point `(x, y)` and updates its
+    /// `ChunkPosition` to match. Also registers entities missing from their cell, e.g. after
+    /// [`Self::reset`].
+    pub fn relocate(&mut self, element: Entity, chunk_pos: &mut ChunkPosition, x: f32, y: f32) {
+        let target = self.chunk_position(x, y);
+        if target != *chunk_pos {
+            self.remove(element, chunk_pos);
+            *chunk_pos = target;
+        }
+        self.insert(element, chunk_pos);
+    }
+
+    /// Removes the entity from its cell and unloads the chunk if it became empty.
+    /// Returns whether the entity was registered at that position.
+    pub fn remove(&mut self, element: Entity, pos: &ChunkPosition) -> bool {
+        let Some(entry) = self.chunks.get_mut(&pos.chunk) else {
+            return false;
+        };
+        let removed = entry
+            .chunk
+            .get_mut(pos.cell.x as usize, pos.cell.y as usize)
+            .is_some_and(|cell| cell.remove(&element));
+        if removed {
+            entry.population -= 1;
+            if entry.population == 0 {
+                self.chunks.remove(&pos.chunk);
             }
-            if dx == 1 && x == CHUNK_SIZE - 1 {
-                return None;
+        }
+        removed
+    }
+
+    pub fn get_cell(&self, pos: &ChunkPosition) -> Option<&HashSet<Entity>> {
+        self.chunks
+            .get(&pos.chunk)?
+            .chunk
+            .get(pos.cell.x as usize, pos.cell.y as usize)
+    }
+
+    pub fn loaded_chunks(&self) -> impl Iterator<Item = (&IVec2, &EntityChunk)> {
+        self.chunks.iter().map(|(pos, entry)| (pos, entry.as_ref()))
+    }
+
+    /// Checks the registry against the positions of all entities that should be in it and returns
+    /// every disagreement, so an empty result means lookups can not hit a despawned entity
+    pub fn validate(
+        &self,
+        positions: impl IntoIterator<Item = (Entity, ChunkPosition)>,
+    ) -> Vec<RegistryMismatch> {
+        let positions: HashMap<Entity, ChunkPosition> = positions.into_iter().collect();
+        let mut mismatches = Vec::new();
+        for (&chunk, entry) in self.chunks.iter() {
+            let mut actual = 0;
+            for (index, cell) in entry.chunk.cells.iter().enumerate() {
+                let pos = ChunkPosition {
+                    chunk,
+                    cell: UVec2::new((index % CHUNK_SIZE) as u32, (index / CHUNK_SIZE) as u32),
+                };
+                for &entity in cell.iter() {
+                    actual += 1;
+                    if positions.get(&entity) != Some(&pos) {
+                        mismatches.push(RegistryMismatch::Dangling { entity, pos });
+                    }
+                }
             }
-            if dy == -1 && y == 0 {
-                return None;
+            if actual != entry.population {
+                mismatches.push(RegistryMismatch::Population {
+                    chunk,
+                    counted: entry.population,
+                    actual,
+                });
             }
-            if dy == 1 && y == CHUNK_SIZE - 1 {
-                return None;
+        }
+        for (&entity, pos) in positions.iter() {
+            if !self
+                .get_cell(pos)
+                .is_some_and(|cell| cell.contains(&entity))
+            {
+                mismatches.push(RegistryMismatch::Missing { entity, pos: *pos });
             }
-            self.get((x_i + dx) as usize, (y_i + dy) as usize)
-        });
+        }
+        mismatches
+    }
 
-        [
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-        ]
-    }
-
-    pub fn get_neighborhood(&self, x: usize, y: usize) -> [Option<&T>; 9] {
-        let mut elements = [
-            (-1, -1),
-            (-1, 0),
-            (-1, 1),
-            (0, -1),
-            (0, 0),
-            (0, 1),
-            (1, -1),
-            (1, 0),
-            (1, 1),
-        ]
-        .into_iter()
-        .map(|(dx, dy)| {
-            let x_i = x as i32;
-            let y_i = y as i32;
-            if dx == -1 && x == 0 {
-                return None;
+    /// Collects the entities of every cell overlapping the rectangle between `min` and `max`
+    pub fn get_area_entities(&self, min: Vec2, max: Vec2) -> Vec<Entity> {
+        let low = self.chunk_position(min.x, min.y).global_cell();
+        let high = self.chunk_position(max.x, max.y).global_cell();
+        let mut entities = Vec::new();
+        for x in low.x..=high.x {
+            for y in low.y..=high.y {
+                let pos = ChunkPosition::from_global_cell(IVec2::new(x, y));
+                if let Some(cell) = self.get_cell(&pos) {
+                    entities.extend(cell.iter().copied());
+                }
             }
-            if dx == 1 && x == CHUNK_SIZE - 1 {
-                return None;
-            }
-            if dy == -1 && y == 0 {
-                return None;
-            }
-            if dy == 1 && y == CHUNK_SIZE - 1 {
-                return None;
+        }
+
+        entities
+    }
+
+    /// Collects the entities of the 3x3 cells around `pos`, crossing chunk borders if needed.
+    pub fn get_neighborhood_entities(&self, pos: &ChunkPosition) -> Vec<Entity> {
+        let center = pos.global_cell();
+        let mut entities = Vec::new();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let neighbor = ChunkPosition::from_global_cell(center + IVec2::new(dx, dy));
+                if let Some(cell) = self.get_cell(&neighbor) {
+                    entities.extend(cell.iter().copied());
+                }
             }
-            self.get((x_i + dx) as usize, (y_i + dy) as usize)
-        });
+        }
 
-        [
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-            elements.next().unwrap(),
-        ]
+        entities
     }
 }
 
-impl Default for EntityLookupChunk {
-    fn default() -> Self {
-        Self(Chunk {
-            cells: array::from_fn(|_| HashSet::new()),
-        })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::particle::{Particle, PredictedPos};
+
+    #[test]
+    fn negative_coordinates_map_below_zero() {
+        let pos = ChunkPosition::from_world(-0.6, -64.4, 1.0);
+        assert_eq!(pos.chunk, IVec2::new(-1, -1));
+        assert_eq!(pos.cell, UVec2::new(63, 0));
+        assert_eq!(pos.global_cell(), IVec2::new(-1, -64));
+        // Cells are centered on integers, so -0.4 still belongs to cell 0 of chunk 0
+        assert_eq!(
+            ChunkPosition::from_world(-0.4, 0.0, 1.0).global_cell(),
+            IVec2::ZERO
+        );
     }
-}
 
-impl EntityLookupChunk {
-    pub fn insert(&mut self, element: Entity, x: usize, y: usize) -> Option<()> {
-        self.0.get_mut(x, y)?.insert(element);
-        Some(())
+    #[test]
+    fn neighborhood_crosses_chunk_borders() {
+        let mut lookup = EntityLookupChunk::default();
+        let cells = [
+            IVec2::new(-1, -1),
+            IVec2::new(-1, 0),
+            IVec2::new(0, -1),
+            IVec2::new(0, 0),
+            IVec2::new(2, 0),
+        ];
+        for (index, cell) in cells.iter().enumerate() {
+            lookup.insert(
+                Entity::from_raw(index as u32),
+                &ChunkPosition::from_global_cell(*cell),
+            );
+        }
+        assert_eq!(lookup.loaded_chunks().count(), 4);
+
+        let mut neighbors =
+            lookup.get_neighborhood_entities(&ChunkPosition::from_global_cell(IVec2::ZERO));
+        neighbors.sort();
+        assert_eq!(neighbors, (0..4).map(Entity::from_raw).collect::<Vec<_>>());
     }
 
-    pub fn get_neighborhood_entities(&self, x: usize, y: usize) -> Vec<Entity> {
-        let neighborhood = self.0.get_neighborhood(x, y);
+    #[test]
+    fn empty_chunk_is_unloaded() {
+        let mut lookup = EntityLookupChunk::default();
+        let pos = ChunkPosition::from_global_cell(IVec2::new(-100, 300));
+        let first = Entity::from_raw(1);
+        let second = Entity::from_raw(2);
+        lookup.insert(first, &pos);
+        lookup.insert(second, &pos);
 
-        let entities = neighborhood
-            .as_slice()
-            .iter()
-            .filter_map(|x| *x)
-            .flatten()
-            .cloned()
-            .collect::<Vec<_>>();
+        assert!(lookup.remove(first, &pos));
+        assert_eq!(lookup.loaded_chunks().count(), 1);
+        assert!(lookup.remove(second, &pos));
+        assert_eq!(lookup.loaded_chunks().count(), 0);
+        // Removing again neither fails nor reloads the chunk
+        assert!(!lookup.remove(second, &pos));
+        assert!(lookup.get_cell(&pos).is_none());
+    }
 
-        entities
+    #[test]
+    fn registry_follows_spawn_and_despawn() {
+        let mut world = World::new();
+        world.insert_resource(EntityLookupChunk::new(1.0));
+
+        let mut spawned = Vec::new();
+        for i in 0..20 {
+            let pos = Vec2::new(i as f32 * 0.7, 3.0);
+            let chunk_position = world
+                .resource::<EntityLookupChunk>()
+                .chunk_position(pos.x, pos.y);
+            spawned.push(
+                world
+                    .spawn((Particle, PredictedPos(pos), chunk_position))
+                    .id(),
+            );
+        }
+        for &entity in spawned.iter().step_by(2) {
+            world.despawn(entity);
+        }
+        // Re-inserting the component moves the particle to its new cell
+        let moved = spawned[1];
+        let target = world
+            .resource::<EntityLookupChunk>()
+            .chunk_position(-70.0, 90.0);
+        world.entity_mut(moved).insert(target);
+        // Moving it in place bypasses the hooks, `relocate` keeps both sides in sync
+        let relocated = spawned[3];
+        world.resource_scope(|world, mut lookup: Mut<EntityLookupChunk>| {
+            let mut chunk_pos = world.get_mut::<ChunkPosition>(relocated).unwrap();
+            lookup.relocate(relocated, &mut chunk_pos, 5.0, -20.0);
+        });
+
+        let positions: Vec<(Entity, ChunkPosition)> = world
+            .query_filtered::<(Entity, &ChunkPosition), With<Particle>>()
+            .iter(&world)
+            .map(|(entity, pos)| (entity, *pos))
+            .collect();
+        assert_eq!(positions.len(), 10);
+        let lookup = world.resource::<EntityLookupChunk>();
+        assert_eq!(lookup.validate(positions), Vec::new());
+        assert_eq!(lookup.get_neighborhood_entities(&target), vec![moved]);
+        assert_eq!(
+            lookup.get_neighborhood_entities(&lookup.chunk_position(5.0, -20.0)),
+            vec![relocated]
+        );
     }
 }
@@ -1,16 +1,33 @@
+use crate::basic_assets::ParticleColoring;
 use crate::camera::MousePosition;
-use crate::chunk::{Chunk, EntityLookupChunk, CHUNK_SIZE};
+use crate::chunk::{ChunkPosition, EntityLookupChunk, CHUNK_SIZE};
+use crate::dfsph::DfsphParameters;
+use crate::domain::{DomainShape, SimDomain};
+use crate::emitter::{EmissionMode, EmitterShape, FluidEmitter, FluidSink};
+use crate::integrator::{Integrator, SimEnergy};
+use crate::kernel::{Kernel, KernelKind};
+use crate::material::{Elasticity, FluidMaterials, Granular, MaterialId, PressureModel, Rheology};
+use crate::obstacle::{ImageObstacle, Obstacle, ObstacleShape, SlipCondition};
 use crate::particle::{
-    get_particle_density, get_particle_mass_density, get_particle_pressure_gradient,
-    LocalMassDensity, Mass, Particle, PredictedPos, SimParameters, Velocity,
+    get_particle_mass_density, get_particle_pressure_gradient, LocalMassDensity, Mass, Particle,
+    PredictedPos, Pressure, SimParameters, Velocity,
 };
+use crate::pbf::PbfParameters;
+use crate::pcisph::PcisphParameters;
+use crate::rigid_body::FluidRigidBody;
+use crate::solver::{SolverKind, SolverStats};
+use crate::thermal::{HeatSource, ThermalParameters, AMBIENT_TEMPERATURE};
+use crate::vorticity::Vorticity;
 use bevy::app::{App, Plugin, Update};
-use bevy::color::palettes::tailwind::{BLUE_200, GREEN_700, ORANGE_400, RED_500};
+use bevy::color::palettes::tailwind::{
+    AMBER_300, BLUE_200, GRAY_400, GREEN_700, ORANGE_400, RED_500, SLATE_300,
+};
 use bevy::math::Vec2;
 use bevy::prelude::*;
 use bevy::prelude::{Gizmos, Query, Resource, Transform, With};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
-use std::cmp::max;
 
 #[derive(Debug, Clone, Resource)]
 pub struct DebugConfig {
@@ -21,6 +38,11 @@ pub struct DebugConfig {
     pub highlight_neighborhood_entities: bool,
     pub show_density_grid: bool,
     pub show_derivative_gizmo: bool,
+    pub show_vorticity: bool,
+    pub show_domain: bool,
+    pub show_obstacles: bool,
+    pub obstacle_no_slip: bool,
+    pub validate_registry: bool,
 }
 
 impl Default for DebugConfig {
@@ -33,6 +55,11 @@ impl Default for DebugConfig {
             highlight_neighborhood_entities: false,
             show_density_grid: false,
             show_derivative_gizmo: false,
+            show_vorticity: false,
+            show_domain: true,
+            show_obstacles: true,
+            obstacle_no_slip: false,
+            validate_registry: false,
         }
     }
 }
@@ -43,7 +70,18 @@ impl Plugin for ParticleDebugPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(DebugConfig::default())
             .add_plugins(EguiPlugin)
-            .add_systems(Update, debug_config_ui)
+            .add_systems(
+                Update,
+                (
+                    debug_config_ui,
+                    debug_time_ui,
+                    debug_solver_ui,
+                    debug_obstacle_ui,
+                    debug_heat_ui,
+                    debug_material_ui,
+                    debug_emitter_ui,
+                ),
+            )
             .add_systems(
                 Update,
                 (
@@ -53,6 +91,12 @@ impl Plugin for ParticleDebugPlugin {
                     highlight_neighborhood_entities.run_if(config_highlight_neighborhood_enabled),
                     density_grid.run_if(config_show_density_grid),
                     derivative_arrow.run_if(config_show_derivative_gizmo_enabled),
+                    vorticity_gizmos.run_if(config_show_vorticity),
+                    domain_gizmos.run_if(config_show_domain),
+                    obstacle_gizmos.run_if(config_show_obstacles),
+                    heat_source_gizmos.run_if(config_show_obstacles),
+                    emitter_gizmos.run_if(config_show_obstacles),
+                    validate_lookup_registry.run_if(config_validate_registry),
                 ),
             );
     }
@@ -67,14 +111,12 @@ pub fn particle_pred_gizmos(mut gizmos: Gizmos, particle_q: Query<&PredictedPos,
 pub fn particle_velocity_gizmos(
     mut gizmos: Gizmos,
     particle_q: Query<(&Velocity, &Transform), With<Particle>>,
+    params: Res<SimParameters>,
 ) {
     for (&Velocity(vel), transf) in particle_q.iter() {
         let start = Vec2::new(transf.translation.x, transf.translation.y);
-        gizmos.arrow_2d(
-            start,
-            Vec2::new(vel.x + start.x, vel.y + start.y),
-            ORANGE_400,
-        );
+        // Scaled to the distance covered during one tick
+        gizmos.arrow_2d(start, start + vel * params.dt, ORANGE_400);
     }
 }
 
@@ -82,12 +124,13 @@ pub fn derivative_arrow(
     mut gizmos: Gizmos,
 
     at_pos: Res<MousePosition>,
-    particles: Query<(&Transform, &LocalMassDensity, &Mass), With<Particle>>,
+    particles: Query<(&PredictedPos, &LocalMassDensity, &Mass, &Pressure), With<Particle>>,
     entity_lookup_chunk: Res<EntityLookupChunk>,
-    pres_mult: Res<SimParameters>,
+    kernel: Res<Kernel>,
 ) {
+    // No particle sits at the cursor, so only the neighbors' pressure contributes
     let derivative =
-        get_particle_pressure_gradient(at_pos.0, &particles, &entity_lookup_chunk, &pres_mult);
+        get_particle_pressure_gradient(at_pos.0, 0.0, &particles, &entity_lookup_chunk, &**kernel);
     if derivative.is_none() {
         return;
     }
@@ -102,12 +145,14 @@ pub fn derivative_arrow(
 
 pub fn local_density_gizmos(
     mut gizmos: Gizmos,
-    particles: Query<(&Transform, &Mass), With<Particle>>,
+    particles: Query<(&PredictedPos, &Mass), With<Particle>>,
     entity_lookup_chunk: Res<EntityLookupChunk>,
     mouse_pos: Res<MousePosition>,
+    kernel: Res<Kernel>,
 ) {
-    let density = get_particle_mass_density(mouse_pos.0, &particles, &entity_lookup_chunk)
-        .unwrap_or_default();
+    let density =
+        get_particle_mass_density(mouse_pos.0, &particles, &entity_lookup_chunk, &**kernel)
+            .unwrap_or_default();
     println!("density: {:?}", density);
     let d = 1.0 - 1.0 / (density.max(0.01) * 10.0);
     let color = Color::Srgba(Srgba::rgb(d, d, d));
@@ -117,8 +162,9 @@ pub fn local_density_gizmos(
 
 pub fn density_grid(
     mut gizmos: Gizmos,
-    particles: Query<(&Transform, &Mass), With<Particle>>,
+    particles: Query<(&PredictedPos, &Mass), With<Particle>>,
     entity_lookup_chunk: Res<EntityLookupChunk>,
+    kernel: Res<Kernel>,
 ) {
     let mut x = 0.0;
     let mut y = 0.0;
@@ -133,6 +179,7 @@ pub fn density_grid(
                         Vec2::new(x_2, y_2),
                         &particles,
                         &entity_lookup_chunk,
+                        &**kernel,
                     )
                     .unwrap_or_default()
                         * 10.0)
@@ -156,14 +203,8 @@ pub fn highlight_neighborhood_entities(
     entity_lookup_chunk: Res<EntityLookupChunk>,
     mouse_pos: Res<MousePosition>,
 ) {
-    let chunk_pos = Chunk::<Vec<Entity>>::get_chunk_pos(mouse_pos.0.x, mouse_pos.0.y);
-    if chunk_pos.is_none() {
-        return;
-    }
-    let chunk_pos = chunk_pos.unwrap();
-
-    let entities =
-        entity_lookup_chunk.get_neighborhood_entities(chunk_pos.x as usize, chunk_pos.y as usize);
+    let chunk_pos = entity_lookup_chunk.chunk_position(mouse_pos.0.x, mouse_pos.0.y);
+    let entities = entity_lookup_chunk.get_neighborhood_entities(&chunk_pos);
     for entity in entities.iter() {
         if let Ok(transform) = particles.get(*entity) {
             gizmos.circle_2d(
@@ -175,6 +216,135 @@ pub fn highlight_neighborhood_entities(
     }
 }
 
+/// Circles growing with the vorticity, red for counter-clockwise and blue for clockwise rotation
+pub fn vorticity_gizmos(
+    mut gizmos: Gizmos,
+    particles: Query<(&Vorticity, &Transform), With<Particle>>,
+) {
+    for (vorticity, transform) in particles.iter() {
+        let radius = (vorticity.0.abs() * 0.01).min(0.5);
+        if radius < 0.02 {
+            continue;
+        }
+        let color = if vorticity.0 > 0.0 { RED_500 } else { BLUE_200 };
+        gizmos.circle_2d(transform.translation.truncate(), radius, color);
+    }
+}
+
+pub fn domain_gizmos(mut gizmos: Gizmos, domain: Res<SimDomain>) {
+    let outline = domain.shape.outline();
+    let closing = outline.first().copied();
+    gizmos.linestrip_2d(outline.into_iter().chain(closing), GRAY_400);
+}
+
+pub fn obstacle_gizmos(
+    mut gizmos: Gizmos,
+    obstacles: Query<(&Obstacle, &Transform)>,
+    bodies: Query<(&FluidRigidBody, &Transform)>,
+) {
+    for (obstacle, transform) in obstacles.iter() {
+        shape_gizmo(&mut gizmos, &obstacle.shape, transform, SLATE_300);
+    }
+    for (body, transform) in bodies.iter() {
+        shape_gizmo(&mut gizmos, &body.shape, transform, AMBER_300);
+    }
+}
+
+fn shape_gizmo(gizmos: &mut Gizmos, shape: &ObstacleShape, transform: &Transform, color: Srgba) {
+    let angle = transform.rotation.to_euler(EulerRot::ZYX).0;
+    let scale = transform.scale.x.abs();
+    let isometry = Isometry2d::new(transform.translation.truncate(), Rot2::radians(angle));
+    match shape {
+        ObstacleShape::Circle { radius } => {
+            gizmos.circle_2d(isometry, radius * scale, color);
+        }
+        ObstacleShape::Capsule {
+            half_length,
+            radius,
+        } => {
+            // Capsule2d is vertical, ours lies along x
+            let upright = Isometry2d::new(
+                isometry.translation,
+                Rot2::radians(angle + std::f32::consts::FRAC_PI_2),
+            );
+            let capsule = Capsule2d::new(radius * scale, half_length * 2.0 * scale);
+            gizmos.primitive_2d(&capsule, upright, color);
+        }
+        ObstacleShape::Box { half_extents } => {
+            gizmos.rect_2d(isometry, *half_extents * 2.0 * scale, color);
+        }
+        ObstacleShape::ConvexPolygon { vertices } => {
+            let points = vertices
+                .iter()
+                .chain(vertices.first())
+                .map(|v| isometry.transform_point(*v * scale));
+            gizmos.linestrip_2d(points, color);
+        }
+        ObstacleShape::Sdf(grid) => {
+            let size = Vec2::new(grid.width as f32, grid.height as f32) * grid.cell_size;
+            gizmos.rect_2d(isometry, size * scale, color);
+        }
+    }
+}
+
+pub fn heat_source_gizmos(mut gizmos: Gizmos, sources: Query<(&HeatSource, &Transform)>) {
+    for (source, transform) in sources.iter() {
+        let color = if source.temperature > AMBIENT_TEMPERATURE {
+            RED_500
+        } else {
+            BLUE_200
+        };
+        gizmos.circle_2d(transform.translation.truncate(), source.radius, color);
+    }
+}
+
+pub fn emitter_gizmos(
+    mut gizmos: Gizmos,
+    emitters: Query<(&FluidEmitter, &Transform)>,
+    sinks: Query<(&FluidSink, &Transform)>,
+) {
+    for (emitter, transform) in emitters.iter() {
+        let center = transform.translation.truncate();
+        match emitter.shape {
+            EmitterShape::Point => {
+                gizmos.circle_2d(center, 0.3, AMBER_300);
+            }
+            EmitterShape::Line { half_length } => {
+                let end = transform.transform_point(Vec3::X * half_length).truncate();
+                gizmos.line_2d(center * 2.0 - end, end, AMBER_300);
+            }
+            EmitterShape::Circle { radius } => {
+                gizmos.circle_2d(center, radius * transform.scale.x.abs(), AMBER_300);
+            }
+        }
+        let velocity = (transform.rotation * emitter.velocity.extend(0.0)).truncate();
+        gizmos.arrow_2d(center, center + velocity * 0.2, AMBER_300);
+    }
+    for (sink, transform) in sinks.iter() {
+        shape_gizmo(&mut gizmos, &sink.shape, transform, GRAY_400);
+    }
+}
+
+/// Reports disagreements between the lookup registry and the particles, once whenever their
+/// number changes instead of every frame
+pub fn validate_lookup_registry(
+    particles: Query<(Entity, &ChunkPosition), With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    mut reported: Local<usize>,
+) {
+    let mismatches = entity_lookup_chunk.validate(particles.iter().map(|(e, pos)| (e, *pos)));
+    if mismatches.len() == *reported {
+        return;
+    }
+    *reported = mismatches.len();
+    if let Some(first) = mismatches.first() {
+        warn!(
+            "Lookup registry has {} mismatches, the first is {first:?}",
+            mismatches.len()
+        );
+    }
+}
+
 pub fn config_pred_gizmo_enabled(debug_config: Res<DebugConfig>) -> bool {
     debug_config.enable_pred_gizmo
 }
@@ -199,10 +369,28 @@ pub fn config_show_derivative_gizmo_enabled(debug_config: Res<DebugConfig>) -> b
     debug_config.show_derivative_gizmo
 }
 
+pub fn config_show_vorticity(debug_config: Res<DebugConfig>) -> bool {
+    debug_config.show_vorticity
+}
+
+pub fn config_show_domain(debug_config: Res<DebugConfig>) -> bool {
+    debug_config.show_domain
+}
+
+pub fn config_show_obstacles(debug_config: Res<DebugConfig>) -> bool {
+    debug_config.show_obstacles
+}
+
+pub fn config_validate_registry(debug_config: Res<DebugConfig>) -> bool {
+    debug_config.validate_registry
+}
+
 pub fn debug_config_ui(
     mut contexts: EguiContexts,
     mut config: ResMut<DebugConfig>,
     mut pressure_mult: ResMut<SimParameters>,
+    mut domain: ResMut<SimDomain>,
+    mut kernel: ResMut<Kernel>,
     mouse_pos: Res<MousePosition>,
 ) {
     let show_mouse_pos = config.show_mouse_pos;
@@ -227,11 +415,509 @@ pub fn debug_config_ui(
         );
         ui.checkbox(&mut config.show_density_grid, "Show Density Grid");
         ui.checkbox(&mut config.show_derivative_gizmo, "Show Derivative");
+        ui.checkbox(&mut config.show_vorticity, "Show Vorticity");
+        ui.checkbox(&mut config.show_domain, "Show Domain");
+        ui.checkbox(&mut config.validate_registry, "Validate Lookup Registry");
+        let equation_of_state = &mut pressure_mult.equation_of_state;
+        egui::ComboBox::from_label("Equation of State")
+            .selected_text(format!("{:?}", equation_of_state.model))
+            .show_ui(ui, |ui| {
+                for option in PressureModel::ALL {
+                    ui.selectable_value(
+                        &mut equation_of_state.model,
+                        option,
+                        format!("{option:?}"),
+                    );
+                }
+            });
+        ui.add(egui::Slider::new(&mut equation_of_state.stiffness, 0.0..=1800.0).text("Stiffness"));
+        if equation_of_state.model == PressureModel::Tait {
+            ui.add(egui::Slider::new(&mut equation_of_state.exponent, 1..=7).text("Tait Exponent"));
+        }
+        ui.checkbox(
+            &mut equation_of_state.clamp_negative,
+            "Clamp Negative Pressure",
+        );
+
+        ui.add(
+            egui::Slider::new(&mut pressure_mult.near_pressure, 0.0..=1800.0).text("Near Pressure"),
+        );
+        ui.add(egui::Slider::new(&mut pressure_mult.gravity, 0.0..=100.0).text("Gravity"));
+        ui.add(egui::Slider::new(&mut pressure_mult.viscosity, 0.0..=30.0).text("Viscosity"));
+        ui.add(egui::Slider::new(&mut pressure_mult.xsph, 0.0..=0.5).text("XSPH Smoothing"));
         ui.add(
-            egui::Slider::new(&mut pressure_mult.pressure_mult, 0.0..=0.2)
-                .text("Pressure Multiplier"),
+            egui::Slider::new(&mut pressure_mult.vorticity_confinement, 0.0..=50.0)
+                .text("Vorticity Confinement"),
         );
+        ui.add(
+            egui::Slider::new(&mut pressure_mult.surface_tension, 0.0..=1800.0)
+                .text("Surface Tension"),
+        );
+        ui.add(egui::Slider::new(&mut pressure_mult.adhesion, 0.0..=720.0).text("Adhesion"));
+        ui.add(
+            egui::Slider::new(&mut pressure_mult.smoothing_radius, 0.25..=3.0)
+                .text("Smoothing Radius"),
+        );
+
+        let mut kind = kernel.kind;
+        egui::ComboBox::from_label("Kernel")
+            .selected_text(format!("{kind:?}"))
+            .show_ui(ui, |ui| {
+                for option in KernelKind::ALL {
+                    ui.selectable_value(&mut kind, option, format!("{option:?}"));
+                }
+            });
+        if kind != kernel.kind {
+            let radius = kernel.radius();
+            *kernel = Kernel::new(kind, radius);
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Domain");
+            if ui.button("Box").clicked() {
+                domain.shape = DomainShape::Box {
+                    min: Vec2::splat(0.5),
+                    max: Vec2::splat(63.0),
+                };
+            }
+            if ui.button("Bowl").clicked() {
+                domain.shape = DomainShape::Circle {
+                    center: Vec2::splat(32.0),
+                    radius: 31.5,
+                };
+            }
+            if ui.button("Funnel").clicked() {
+                domain.shape = DomainShape::Polygon {
+                    vertices: vec![
+                        Vec2::new(0.5, 63.0),
+                        Vec2::new(0.5, 32.0),
+                        Vec2::new(28.0, 8.0),
+                        Vec2::new(28.0, 0.5),
+                        Vec2::new(36.0, 0.5),
+                        Vec2::new(36.0, 8.0),
+                        Vec2::new(63.0, 32.0),
+                        Vec2::new(63.0, 63.0),
+                    ],
+                };
+            }
+        });
+    });
+}
+
+pub fn debug_time_ui(
+    mut contexts: EguiContexts,
+    mut params: ResMut<SimParameters>,
+    mut integrator: ResMut<Integrator>,
+    mut energy: ResMut<SimEnergy>,
+) {
+    egui::Window::new("Time Integration").show(contexts.ctx_mut(), |ui| {
+        ui.add(egui::Slider::new(&mut params.dt, 1.0 / 240.0..=1.0 / 20.0).text("Timestep (s)"));
+        ui.checkbox(&mut params.adaptive_steps, "Adaptive Substeps (CFL)");
+        if params.adaptive_steps {
+            ui.add(egui::Slider::new(&mut params.cfl, 0.05..=1.0).text("CFL Number"));
+            ui.add(
+                egui::Slider::new(&mut params.min_step, 1.0 / 4000.0..=1.0 / 60.0)
+                    .logarithmic(true)
+                    .text("Min Substep (s)"),
+            );
+            ui.add(
+                egui::Slider::new(&mut params.max_step, 1.0 / 4000.0..=1.0 / 20.0)
+                    .logarithmic(true)
+                    .text("Max Substep (s)"),
+            );
+        } else {
+            ui.add(egui::Slider::new(&mut params.substeps, 1..=16).text("Substeps"));
+        }
+        let mut scheme = *integrator;
+        egui::ComboBox::from_label("Integrator")
+            .selected_text(format!("{scheme:?}"))
+            .show_ui(ui, |ui| {
+                for option in Integrator::ALL {
+                    ui.selectable_value(&mut scheme, option, format!("{option:?}"));
+                }
+            });
+        if scheme != *integrator {
+            *integrator = scheme;
+            energy.reference = None;
+        }
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "Energy: {:.1} (drift {:+.2}%)",
+                energy.total(),
+                energy.drift() * 100.0
+            ));
+            if ui.button("Reset").clicked() {
+                energy.reference = None;
+            }
+        });
+    });
+}
+
+pub fn debug_solver_ui(
+    mut contexts: EguiContexts,
+    mut solver: ResMut<SolverKind>,
+    mut pbf: ResMut<PbfParameters>,
+    mut pcisph: ResMut<PcisphParameters>,
+    mut dfsph: ResMut<DfsphParameters>,
+    stats: Res<SolverStats>,
+) {
+    egui::Window::new("Solver").show(contexts.ctx_mut(), |ui| {
+        let mut kind = *solver;
+        egui::ComboBox::from_label("Solver")
+            .selected_text(format!("{kind:?}"))
+            .show_ui(ui, |ui| {
+                for option in SolverKind::ALL {
+                    ui.selectable_value(&mut kind, option, format!("{option:?}"));
+                }
+            });
+        if kind != *solver {
+            *solver = kind;
+        }
+
+        if *solver == SolverKind::PositionBased {
+            ui.add(egui::Slider::new(&mut pbf.iterations, 1..=20).text("Iterations"));
+            ui.add(
+                egui::Slider::new(&mut pbf.relaxation, 0.01..=10.0)
+                    .logarithmic(true)
+                    .text("Relaxation"),
+            );
+            ui.add(
+                egui::Slider::new(&mut pbf.tensile_strength, 0.0..=0.5).text("Tensile Strength"),
+            );
+        }
+
+        if *solver == SolverKind::Pcisph {
+            ui.add(
+                egui::Slider::new(&mut pcisph.tolerance, 0.001..=0.1)
+                    .logarithmic(true)
+                    .text("Density Tolerance"),
+            );
+            ui.add(egui::Slider::new(&mut pcisph.min_iterations, 1..=10).text("Min Iterations"));
+            ui.add(egui::Slider::new(&mut pcisph.max_iterations, 1..=200).text("Max Iterations"));
+            ui.label(format!(
+                "Iterations: {} | Density Error: {:.2}% mean, {:.2}% max",
+                stats.iterations,
+                stats.residual * 100.0,
+                stats.max_error * 100.0
+            ));
+        }
+
+        if *solver == SolverKind::Dfsph {
+            ui.add(
+                egui::Slider::new(&mut dfsph.density_tolerance, 0.001..=0.1)
+                    .logarithmic(true)
+                    .text("Density Tolerance"),
+            );
+            ui.checkbox(&mut dfsph.divergence_solve, "Divergence-Free Solve");
+            ui.add(
+                egui::Slider::new(&mut dfsph.divergence_tolerance, 0.01..=1.0)
+                    .logarithmic(true)
+                    .text("Divergence Tolerance"),
+            );
+            ui.add(egui::Slider::new(&mut dfsph.min_iterations, 1..=10).text("Min Iterations"));
+            ui.add(egui::Slider::new(&mut dfsph.max_iterations, 1..=200).text("Max Iterations"));
+            ui.label(format!(
+                "Density Solve: {} iterations | {:.2}% mean, {:.2}% max",
+                stats.iterations,
+                stats.residual * 100.0,
+                stats.max_error * 100.0
+            ));
+            ui.label(format!(
+                "Divergence Solve: {} iterations | {:.2}% mean",
+                stats.divergence_iterations,
+                stats.divergence_residual * 100.0
+            ));
+        }
+    });
+}
+
+pub fn debug_obstacle_ui(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut config: ResMut<DebugConfig>,
+    mut images: ResMut<Assets<Image>>,
+    obstacles: Query<Entity, With<Obstacle>>,
+    bodies: Query<Entity, With<FluidRigidBody>>,
+) {
+    egui::Window::new("Obstacles").show(contexts.ctx_mut(), |ui| {
+        ui.checkbox(&mut config.show_obstacles, "Show Obstacles");
+        ui.checkbox(&mut config.obstacle_no_slip, "No-Slip");
+        let slip = if config.obstacle_no_slip {
+            SlipCondition::NoSlip
+        } else {
+            SlipCondition::FreeSlip
+        };
+        let spawn_at = Transform::from_xyz(32.0, 20.0, 0.0);
+
+        let shape = ui
+            .horizontal(|ui| {
+                let mut shape = None;
+                if ui.button("Circle").clicked() {
+                    shape = Some(ObstacleShape::Circle { radius: 4.0 });
+                }
+                if ui.button("Capsule").clicked() {
+                    shape = Some(ObstacleShape::Capsule {
+                        half_length: 6.0,
+                        radius: 2.0,
+                    });
+                }
+                if ui.button("Box").clicked() {
+                    shape = Some(ObstacleShape::Box {
+                        half_extents: Vec2::new(6.0, 3.0),
+                    });
+                }
+                if ui.button("Wedge").clicked() {
+                    shape = Some(ObstacleShape::ConvexPolygon {
+                        vertices: vec![
+                            Vec2::new(-6.0, -3.0),
+                            Vec2::new(6.0, -3.0),
+                            Vec2::new(0.0, 4.0),
+                        ],
+                    });
+                }
+                shape
+            })
+            .inner;
+        if let Some(shape) = shape {
+            commands.spawn((Obstacle { shape, slip }, spawn_at));
+        }
+
+        ui.horizontal(|ui| {
+            if ui.button("Ring Image").clicked() {
+                commands.spawn((
+                    ImageObstacle {
+                        image: images.add(ring_image(32)),
+                        cell_size: 0.3,
+                        threshold: 0.5,
+                        slip,
+                    },
+                    spawn_at,
+                ));
+            }
+            if ui.button("Boat").clicked() {
+                let hull = ObstacleShape::Box {
+                    half_extents: Vec2::new(5.0, 1.5),
+                };
+                commands.spawn((
+                    FluidRigidBody::from_density(hull, 1.0),
+                    Transform::from_xyz(32.0, 50.0, 0.0),
+                ));
+            }
+            if ui.button("Crate").clicked() {
+                let crate_shape = ObstacleShape::Box {
+                    half_extents: Vec2::splat(2.0),
+                };
+                commands.spawn((
+                    FluidRigidBody::from_density(crate_shape, 6.0),
+                    Transform::from_xyz(32.0, 50.0, 0.0),
+                ));
+            }
+            if ui.button("Clear").clicked() {
+                for entity in obstacles.iter().chain(bodies.iter()) {
+                    commands.entity(entity).despawn();
+                }
+            }
+        });
+    });
+}
+
+pub fn debug_heat_ui(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut thermal: ResMut<ThermalParameters>,
+    mut coloring: ResMut<ParticleColoring>,
+    sources: Query<Entity, With<HeatSource>>,
+) {
+    egui::Window::new("Heat").show(contexts.ctx_mut(), |ui| {
+        let mut mode = *coloring;
+        egui::ComboBox::from_label("Particle Color")
+            .selected_text(format!("{mode:?}"))
+            .show_ui(ui, |ui| {
+                for option in ParticleColoring::ALL {
+                    ui.selectable_value(&mut mode, option, format!("{option:?}"));
+                }
+            });
+        if mode != *coloring {
+            *coloring = mode;
+        }
+        ui.add(egui::Slider::new(&mut thermal.diffusivity, 0.0..=5.0).text("Diffusivity"));
+        ui.add(egui::Slider::new(&mut thermal.expansion, 0.0..=0.05).text("Thermal Expansion"));
 
-        ui.add(egui::Slider::new(&mut pressure_mult.gravity, 0.0..=0.03).text("Gravity"));
+        ui.horizontal(|ui| {
+            if ui.button("Heater").clicked() {
+                commands.spawn((
+                    HeatSource {
+                        radius: 4.0,
+                        temperature: 100.0,
+                        rate: 2.0,
+                    },
+                    Transform::from_xyz(32.0, 4.0, 0.0),
+                ));
+            }
+            if ui.button("Cooler").clicked() {
+                commands.spawn((
+                    HeatSource {
+                        radius: 4.0,
+                        temperature: 0.0,
+                        rate: 2.0,
+                    },
+                    Transform::from_xyz(32.0, 40.0, 0.0),
+                ));
+            }
+            if ui.button("Clear").clicked() {
+                for entity in sources.iter() {
+                    commands.entity(entity).despawn();
+                }
+            }
+        });
     });
 }
+
+pub fn debug_emitter_ui(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    emitters: Query<Entity, With<FluidEmitter>>,
+    sinks: Query<Entity, With<FluidSink>>,
+) {
+    egui::Window::new("Emitters").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            if ui.button("Fountain").clicked() {
+                commands.spawn((
+                    FluidEmitter {
+                        rate: 40.0,
+                        // Pointing right once the line is turned upright
+                        velocity: Vec2::new(0.0, -12.0),
+                        spread: 0.2,
+                        material: MaterialId(0),
+                        shape: EmitterShape::Line { half_length: 1.5 },
+                        ..default()
+                    },
+                    Transform::from_xyz(6.0, 56.0, 0.0)
+                        .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2)),
+                ));
+            }
+            if ui.button("Sand Burst").clicked() {
+                commands.spawn((
+                    FluidEmitter {
+                        material: MaterialId(3),
+                        shape: EmitterShape::Circle { radius: 3.0 },
+                        mode: EmissionMode::Burst {
+                            count: 60,
+                            interval: None,
+                        },
+                        ..default()
+                    },
+                    Transform::from_xyz(32.0, 56.0, 0.0),
+                ));
+            }
+            if ui.button("Drip").clicked() {
+                commands.spawn((
+                    FluidEmitter {
+                        velocity: Vec2::new(0.0, -2.0),
+                        material: MaterialId(1),
+                        mode: EmissionMode::Burst {
+                            count: 1,
+                            interval: Some(0.25),
+                        },
+                        ..default()
+                    },
+                    Transform::from_xyz(48.0, 58.0, 0.0),
+                ));
+            }
+            if ui.button("Drain").clicked() {
+                commands.spawn((
+                    FluidSink {
+                        shape: ObstacleShape::Box {
+                            half_extents: Vec2::new(4.0, 2.0),
+                        },
+                    },
+                    Transform::from_xyz(58.0, 3.0, 0.0),
+                ));
+            }
+            if ui.button("Clear").clicked() {
+                for entity in emitters.iter().chain(sinks.iter()) {
+                    commands.entity(entity).despawn();
+                }
+            }
+        });
+    });
+}
+
+pub fn debug_material_ui(mut contexts: EguiContexts, mut materials: ResMut<FluidMaterials>) {
+    egui::Window::new("Materials").show(contexts.ctx_mut(), |ui| {
+        let mut ids: Vec<usize> = materials.materials.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let Some(material) = materials.materials.get_mut(&id) else {
+                continue;
+            };
+            let mut rheology = material.rheology;
+            egui::ComboBox::from_label(format!("Material {id} Rheology"))
+                .selected_text(rheology.name())
+                .show_ui(ui, |ui| {
+                    for option in Rheology::ALL {
+                        ui.selectable_value(&mut rheology, option, option.name());
+                    }
+                });
+            if rheology != material.rheology {
+                material.rheology = rheology;
+            }
+
+            let mut elastic = material.elasticity.is_some();
+            ui.checkbox(&mut elastic, format!("Material {id} Elastic"));
+            if elastic != material.elasticity.is_some() {
+                material.elasticity = elastic.then(Elasticity::default);
+            }
+            if let Some(elasticity) = material.elasticity.as_mut() {
+                ui.add(
+                    egui::Slider::new(&mut elasticity.stiffness, 0.0..=5000.0)
+                        .text("Spring Stiffness"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut elasticity.yield_ratio, 0.0..=0.5).text("Yield Ratio"),
+                );
+                ui.add(egui::Slider::new(&mut elasticity.plasticity, 0.0..=5.0).text("Plasticity"));
+            }
+
+            let mut granular = material.granular.is_some();
+            ui.checkbox(&mut granular, format!("Material {id} Granular"));
+            if granular != material.granular.is_some() {
+                material.granular = granular.then(Granular::default);
+            }
+            if let Some(granular) = material.granular.as_mut() {
+                ui.add(
+                    egui::Slider::new(&mut granular.friction_angle, 0.0..=60.0)
+                        .text("Angle of Repose"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut granular.wet_friction, 0.0..=1.0).text("Wet Friction"),
+                );
+            }
+        }
+    });
+}
+
+/// Grayscale test image of a thick ring, used to try out image based obstacles
+fn ring_image(size: u32) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 255],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::all(),
+    );
+    let center = Vec2::splat(size as f32 * 0.5);
+    for x in 0..size {
+        for y in 0..size {
+            let dist = Vec2::new(x as f32 + 0.5, y as f32 + 0.5).distance(center) / center.x;
+            if (0.5..0.9).contains(&dist) {
+                let _ = image.set_color_at(x, y, Color::WHITE);
+            }
+        }
+    }
+    image
+}
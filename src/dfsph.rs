@@ -0,0 +1,497 @@
+use crate::chunk::EntityLookupChunk;
+use crate::kernel::Kernel;
+use crate::particle::{
+    rest_number_density, Acceleration, LocalMassDensity, Mass, Particle, PredictedPos, Pressure,
+    SimParameters, Velocity,
+};
+use crate::solver::SolverStats;
+use crate::thermal::{Temperature, ThermalParameters};
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::prelude::*;
+
+/// One iteration of the divergence-free solve
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DfsphDivergenceIteration;
+
+/// One iteration of the constant density solve
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DfsphDensityIteration;
+
+/// Settings of the DFSPH solver
+#[derive(Resource, Clone, Debug)]
+pub struct DfsphParameters {
+    /// Mean relative density error the constant density solve stops at
+    pub density_tolerance: f32,
+    /// Mean relative density change per step the divergence-free solve stops at
+    pub divergence_tolerance: f32,
+    /// Skipping the divergence-free solve is cheaper but needs smaller steps
+    pub divergence_solve: bool,
+    pub min_iterations: u32,
+    pub max_iterations: u32,
+}
+
+impl Default for DfsphParameters {
+    fn default() -> Self {
+        Self {
+            density_tolerance: 0.01,
+            divergence_tolerance: 0.1,
+            divergence_solve: true,
+            min_iterations: 2,
+            max_iterations: 100,
+        }
+    }
+}
+
+/// Per particle state of both DFSPH solves
+#[derive(Component, Default, Clone, Debug)]
+pub struct DfsphState {
+    /// The DFSPH factor `α_i`, only depends on the neighborhood
+    pub factor: f32,
+    /// Stiffness `κ_i` of the current iteration
+    pub kappa: f32,
+    /// Mass density this particle is driven towards
+    pub rest_density: f32,
+    /// Pressure `ρ_i Σ κ_i` the constant density solve applied over its iterations
+    pub pressure: f32,
+}
+
+/// `Σ (v_i - v_j) · ∇W_ij`, how fast the number density around the particle grows. The mass
+/// density `ρ_i = m_i Σ W_ij` changes `m_i` times as fast.
+fn density_change_rate(
+    entity: Entity,
+    pos: Vec2,
+    vel: Vec2,
+    particles: &Query<(Entity, &PredictedPos, &Velocity, &Mass, &mut DfsphState), With<Particle>>,
+    entity_lookup_chunk: &EntityLookupChunk,
+    kernel: &Kernel,
+) -> f32 {
+    let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+    let mut rate = 0.0;
+    for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+        if other == entity {
+            continue;
+        }
+        let (_, other_predicted, other_vel, _, _) = particles
+            .get(other)
+            .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+        let diff = pos - other_predicted.0;
+        let dist = diff.length();
+        if dist >= kernel.radius() || dist <= 0.000001 {
+            continue;
+        }
+        rate += (vel - other_vel.0).dot(kernel.gradient_at(diff));
+    }
+    rate
+}
+
+/// Computes the factors `α_i = ρ_i / (m_i (Σ m_j ∇W_ij) · (Σ ∇W_ij) + m_i² Σ |∇W_ij|²)` at the
+/// current positions. The density is `m_i Σ W_ij`, so particles of a heavier phase do not count
+/// as compression of their lighter neighbors; with equal masses this is the usual DFSPH factor.
+/// Warm particles take more room and are driven towards a lower rest density.
+pub fn dfsph_compute_factors(
+    mut particles: Query<
+        (Entity, &PredictedPos, &Mass, &Temperature, &mut DfsphState),
+        With<Particle>,
+    >,
+    read_particles: Query<(&PredictedPos, &Mass), With<Particle>>,
+    densities: Query<&LocalMassDensity, With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    thermal: Res<ThermalParameters>,
+    kernel: Res<Kernel>,
+) {
+    let rest_density = rest_number_density(&**kernel, &entity_lookup_chunk);
+    for (entity, predicted, mass, temperature, mut state) in particles.iter_mut() {
+        let Ok(density) = densities.get(entity) else {
+            continue;
+        };
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        let mut gradient_sum = Vec2::ZERO;
+        let mut mass_gradient_sum = Vec2::ZERO;
+        let mut gradient_sq = 0.0;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            if other == entity {
+                continue;
+            }
+            let (other_predicted, other_mass) = read_particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let gradient = kernel.gradient_at(diff);
+            gradient_sum += gradient;
+            mass_gradient_sum += gradient * other_mass.0;
+            gradient_sq += gradient.length_squared();
+        }
+        let denominator =
+            mass.0 * mass_gradient_sum.dot(gradient_sum) + mass.0 * mass.0 * gradient_sq;
+
+        state.factor = if denominator > f32::EPSILON {
+            density.0 / denominator
+        } else {
+            0.0
+        };
+        state.rest_density = rest_density * mass.0 / thermal.expansion_factor(temperature.0);
+        state.kappa = 0.0;
+        state.pressure = 0.0;
+    }
+}
+
+/// Stiffness that removes the compressing part of the velocity divergence
+pub fn dfsph_divergence_error(
+    mut particles: Query<
+        (Entity, &PredictedPos, &Velocity, &Mass, &mut DfsphState),
+        With<Particle>,
+    >,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    mut stats: ResMut<SolverStats>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    let dt = params.step_dt();
+    let mut kappas = Vec::new();
+    let mut total_error = 0.0;
+    for (entity, predicted, vel, mass, state) in particles.iter() {
+        let rate = mass.0
+            * density_change_rate(
+                entity,
+                predicted.0,
+                vel.0,
+                &particles,
+                &entity_lookup_chunk,
+                &kernel,
+            )
+            .max(0.0);
+        if state.rest_density > 0.0 {
+            total_error += rate * dt / state.rest_density;
+        }
+        kappas.push((entity, rate * state.factor / dt));
+    }
+
+    stats.divergence_residual = mean(total_error, kappas.len());
+    for (entity, kappa) in kappas {
+        if let Ok((_, _, _, _, mut state)) = particles.get_mut(entity) {
+            state.kappa = kappa;
+        }
+    }
+}
+
+/// Stiffness that brings the density predicted after this step back to the rest density
+pub fn dfsph_density_error(
+    mut particles: Query<
+        (Entity, &PredictedPos, &Velocity, &Mass, &mut DfsphState),
+        With<Particle>,
+    >,
+    densities: Query<&LocalMassDensity, With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    mut stats: ResMut<SolverStats>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    let dt = params.step_dt();
+    let mut kappas = Vec::new();
+    let mut total_error = 0.0;
+    let mut max_error = 0.0f32;
+    for (entity, predicted, vel, mass, state) in particles.iter() {
+        let Ok(density) = densities.get(entity) else {
+            continue;
+        };
+        let rate = mass.0
+            * density_change_rate(
+                entity,
+                predicted.0,
+                vel.0,
+                &particles,
+                &entity_lookup_chunk,
+                &kernel,
+            );
+        let error = (density.0 + dt * rate - state.rest_density).max(0.0);
+        if state.rest_density > 0.0 {
+            let relative = error / state.rest_density;
+            total_error += relative;
+            max_error = max_error.max(relative);
+        }
+        kappas.push((entity, error * state.factor / (dt * dt), density.0));
+    }
+
+    stats.residual = mean(total_error, kappas.len());
+    stats.max_error = max_error;
+    for (entity, kappa, density) in kappas {
+        if let Ok((_, _, _, _, mut state)) = particles.get_mut(entity) {
+            state.kappa = kappa;
+            // The velocity correction is the pressure acceleration with `p_i / ρ_i² = κ_i / ρ_i`
+            state.pressure += kappa * density;
+        }
+    }
+}
+
+fn mean(total: f32, count: usize) -> f32 {
+    if count > 0 {
+        total / count as f32
+    } else {
+        0.0
+    }
+}
+
+/// `v_i -= dt Σ m_j (κ_i / ρ_i + κ_j / ρ_j) ∇W_ij`, shared by both solves
+pub fn dfsph_correct_velocity(
+    particles: Query<
+        (Entity, &PredictedPos, &LocalMassDensity, &Mass, &DfsphState),
+        With<Particle>,
+    >,
+    mut velocities: Query<&mut Velocity, With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    let dt = params.step_dt();
+    let mut corrections = Vec::new();
+    for (entity, predicted, density, _, state) in particles.iter() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+        let own_term = state.kappa / density.0;
+
+        let mut correction = Vec2::ZERO;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            if other == entity {
+                continue;
+            }
+            let (_, other_predicted, other_density, other_mass, other_state) = particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let other_term = other_state.kappa / other_density.0;
+            correction -= kernel.gradient_at(diff) * other_mass.0 * (own_term + other_term) * dt;
+        }
+        if correction.is_finite() {
+            corrections.push((entity, correction));
+        }
+    }
+    for (entity, correction) in corrections {
+        if let Ok(mut vel) = velocities.get_mut(entity) {
+            vel.0 += correction;
+        }
+    }
+}
+
+/// Repeats a solve until its residual is within tolerance, returning the iteration count
+fn iterate_solve(
+    world: &mut World,
+    schedule: impl ScheduleLabel + Clone,
+    tolerance: f32,
+    residual: fn(&SolverStats) -> f32,
+) -> u32 {
+    let settings = world.resource::<DfsphParameters>().clone();
+    let mut iterations = 0;
+    loop {
+        world.run_schedule(schedule.clone());
+        iterations += 1;
+
+        let converged = iterations >= settings.min_iterations
+            && residual(world.resource::<SolverStats>()) <= tolerance;
+        if converged || iterations >= settings.max_iterations {
+            return iterations;
+        }
+    }
+}
+
+/// Makes the velocity field divergence free before the other forces are applied
+pub fn run_dfsph_divergence_solve(world: &mut World) {
+    let settings = world.resource::<DfsphParameters>().clone();
+    if !settings.divergence_solve {
+        let mut stats = world.resource_mut::<SolverStats>();
+        stats.divergence_iterations = 0;
+        stats.divergence_residual = 0.0;
+        return;
+    }
+    let iterations = iterate_solve(
+        world,
+        DfsphDivergenceIteration,
+        settings.divergence_tolerance,
+        |stats| stats.divergence_residual,
+    );
+    world.resource_mut::<SolverStats>().divergence_iterations = iterations;
+}
+
+/// Corrects the velocities so the density after the step matches the rest density
+pub fn run_dfsph_density_solve(world: &mut World) {
+    let tolerance = world.resource::<DfsphParameters>().density_tolerance;
+    let iterations = iterate_solve(world, DfsphDensityIteration, tolerance, |stats| {
+        stats.residual
+    });
+    world.resource_mut::<SolverStats>().iterations = iterations;
+}
+
+/// Hands the pressure of the density solve to the rigid bodies, obstacles and granular friction,
+/// which see it from the next step on
+pub fn dfsph_store_pressure(mut particles: Query<(&DfsphState, &mut Pressure), With<Particle>>) {
+    for (state, mut pressure) in particles.iter_mut() {
+        pressure.0 = state.pressure;
+    }
+}
+
+/// Applies the non-pressure forces to the velocities before the density solve
+pub fn dfsph_apply_forces(
+    mut particles: Query<(&mut Velocity, &Acceleration), With<Particle>>,
+    params: Res<SimParameters>,
+) {
+    let dt = params.step_dt();
+    for (mut vel, acc) in particles.iter_mut() {
+        vel.0 += acc.0 * dt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ChunkPosition;
+    use crate::kernel::KernelKind;
+    use crate::particle::{calc_local_mass_density, NearDensity};
+
+    const SPACING: f32 = 0.8;
+
+    /// Square lattice of two phases with a 1:7 mass ratio meeting at `x = 6`, moving with
+    /// `squeeze` times the offset towards its center
+    fn two_phase_lattice(squeeze: f32) -> World {
+        let mut world = World::new();
+        world.insert_resource(Kernel::new(KernelKind::default(), 1.0));
+        // Cells as large as the lattice spacing, so the rest density is that of this lattice
+        world.insert_resource(EntityLookupChunk::new(SPACING));
+        world.init_resource::<SimParameters>();
+        world.init_resource::<SolverStats>();
+        world.init_resource::<ThermalParameters>();
+
+        let center = Vec2::splat(5.5 * SPACING);
+        for x in 0..12 {
+            for y in 0..12 {
+                let pos = Vec2::new(x as f32, y as f32) * SPACING;
+                let mass = if x < 6 { 1.0 } else { 7.0 };
+                let chunk_position = world
+                    .resource::<EntityLookupChunk>()
+                    .chunk_position(pos.x, pos.y);
+                world.spawn((
+                    Particle,
+                    PredictedPos(pos),
+                    Velocity((center - pos) * squeeze),
+                    Mass(mass),
+                    LocalMassDensity::default(),
+                    NearDensity::default(),
+                    DfsphState::default(),
+                    Pressure::default(),
+                    Temperature::default(),
+                    chunk_position,
+                ));
+            }
+        }
+        world
+    }
+
+    fn density_error_schedule() -> Schedule {
+        let mut schedule = Schedule::default();
+        schedule.add_systems(
+            (
+                calc_local_mass_density,
+                dfsph_compute_factors,
+                dfsph_density_error,
+                dfsph_store_pressure,
+            )
+                .chain(),
+        );
+        schedule
+    }
+
+    #[test]
+    fn density_error_vanishes_at_rest() {
+        let mut world = two_phase_lattice(0.0);
+        density_error_schedule().run(&mut world);
+
+        let stats = world.resource::<SolverStats>();
+        assert!(
+            stats.max_error < 1e-4,
+            "density error {} in a lattice at rest",
+            stats.max_error
+        );
+    }
+
+    #[test]
+    fn predicted_density_follows_motion() {
+        let mut world = two_phase_lattice(0.5);
+        density_error_schedule().run(&mut world);
+        let dt = world.resource::<SimParameters>().step_dt();
+
+        // Density error predicted for the end of the step, from the stiffness and factor
+        let predicted: Vec<(Entity, f32)> = world
+            .query::<(Entity, &DfsphState)>()
+            .iter(&world)
+            .filter(|(_, state)| state.factor > 0.0)
+            .map(|(entity, state)| (entity, state.kappa * dt * dt / state.factor))
+            .collect();
+
+        for (mut predicted_pos, vel) in world
+            .query::<(&mut PredictedPos, &Velocity)>()
+            .iter_mut(&mut world)
+        {
+            predicted_pos.0 += vel.0 * dt;
+        }
+        let mut measure = Schedule::default();
+        measure.add_systems(calc_local_mass_density);
+        measure.run(&mut world);
+
+        let mut checked = 0;
+        for (entity, predicted_error) in predicted {
+            let pos = world.get::<ChunkPosition>(entity).unwrap().global_cell();
+            // The border of the lattice is under-dense, which the solve ignores
+            if !(2..10).contains(&pos.x) || !(2..10).contains(&pos.y) {
+                continue;
+            }
+            let state = world.get::<DfsphState>(entity).unwrap();
+            let actual_error =
+                world.get::<LocalMassDensity>(entity).unwrap().0 - state.rest_density;
+            assert!(
+                (predicted_error - actual_error).abs() <= 0.05 * actual_error.abs(),
+                "predicted density error {predicted_error} but got {actual_error} at {pos}"
+            );
+            checked += 1;
+        }
+        assert_eq!(checked, 64);
+    }
+
+    #[test]
+    fn compression_is_stored_as_pressure() {
+        let mut world = two_phase_lattice(0.0);
+        density_error_schedule().run(&mut world);
+        let at_rest = world
+            .query::<&Pressure>()
+            .iter(&world)
+            .fold(0.0f32, |max, pressure| max.max(pressure.0.abs()));
+
+        let mut world = two_phase_lattice(0.5);
+        density_error_schedule().run(&mut world);
+        let mut checked = 0;
+        for (chunk_pos, density, state, pressure) in world
+            .query::<(&ChunkPosition, &LocalMassDensity, &DfsphState, &Pressure)>()
+            .iter(&world)
+        {
+            let pos = chunk_pos.global_cell();
+            if !(2..10).contains(&pos.x) || !(2..10).contains(&pos.y) {
+                continue;
+            }
+            assert!(
+                pressure.0 > 100.0 * at_rest,
+                "pressure {} at {pos} in the compressed lattice, {at_rest} at rest",
+                pressure.0
+            );
+            assert!((pressure.0 - state.kappa * density.0).abs() <= 1e-3 * pressure.0);
+            checked += 1;
+        }
+        assert_eq!(checked, 64);
+    }
+}
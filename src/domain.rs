@@ -0,0 +1,238 @@
+use bevy::prelude::*;
+
+/// How a wall reacts to particles hitting it.
+#[derive(Clone, Copy, Debug)]
+pub struct WallProperties {
+    /// Fraction of the normal velocity that is kept (and reflected) on impact
+    pub restitution: f32,
+    /// Fraction of the tangential velocity that is removed on impact
+    pub friction: f32,
+    /// Key into the adhesion table of `FluidMaterials`
+    pub solid_material: usize,
+}
+
+impl Default for WallProperties {
+    fn default() -> Self {
+        Self {
+            restitution: 0.7,
+            friction: 0.0,
+            solid_material: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum DomainShape {
+    /// Walls are indexed left, right, bottom, top
+    Box { min: Vec2, max: Vec2 },
+    /// A single wall with index 0
+    Circle { center: Vec2, radius: f32 },
+    /// Simple polygon in either winding order; wall `i` is the edge from vertex `i` to `i + 1`
+    Polygon { vertices: Vec<Vec2> },
+}
+
+/// A wall the point is currently behind.
+#[derive(Clone, Copy, Debug)]
+pub struct WallContact {
+    pub wall: usize,
+    /// Unit normal pointing into the domain
+    pub normal: Vec2,
+    /// Closest point on the wall
+    pub point: Vec2,
+}
+
+/// The container the fluid lives in. Particles leaving it are projected back onto the closest
+/// wall, using that wall's [`WallProperties`].
+#[derive(Resource, Clone, Debug)]
+pub struct SimDomain {
+    pub shape: DomainShape,
+    /// Per-wall overrides, indexed like the walls of [`DomainShape`]
+    pub walls: Vec<WallProperties>,
+    /// Used for every wall without an override
+    pub default_wall: WallProperties,
+}
+
+impl Default for SimDomain {
+    fn default() -> Self {
+        Self {
+            shape: DomainShape::Box {
+                min: Vec2::splat(0.5),
+                max: Vec2::splat(63.0),
+            },
+            walls: Vec::new(),
+            default_wall: WallProperties::default(),
+        }
+    }
+}
+
+impl DomainShape {
+    pub fn contains(&self, pos: Vec2) -> bool {
+        match self {
+            DomainShape::Box { min, max } => {
+                pos.x >= min.x && pos.x <= max.x && pos.y >= min.y && pos.y <= max.y
+            }
+            DomainShape::Circle { center, radius } => {
+                pos.distance_squared(*center) <= radius * radius
+            }
+            DomainShape::Polygon { vertices } => polygon_contains(vertices, pos),
+        }
+    }
+
+    /// Returns the wall the point has crossed the furthest, or `None` if it is inside.
+    pub fn contact(&self, pos: Vec2) -> Option<WallContact> {
+        if self.contains(pos) {
+            return None;
+        }
+        match self {
+            DomainShape::Box { min, max } => {
+                let candidates = [
+                    (0, Vec2::X, min.x - pos.x),
+                    (1, Vec2::NEG_X, pos.x - max.x),
+                    (2, Vec2::Y, min.y - pos.y),
+                    (3, Vec2::NEG_Y, pos.y - max.y),
+                ];
+                let (wall, normal, depth) =
+                    candidates.into_iter().max_by(|a, b| a.2.total_cmp(&b.2))?;
+                Some(WallContact {
+                    wall,
+                    normal,
+                    point: pos + normal * depth,
+                })
+            }
+            DomainShape::Circle { center, radius } => {
+                let normal = (*center - pos).normalize_or(Vec2::Y);
+                Some(WallContact {
+                    wall: 0,
+                    normal,
+                    point: *center - normal * *radius,
+                })
+            }
+            DomainShape::Polygon { vertices } => {
+                let (wall, point) = closest_polygon_edge(vertices, pos)?;
+                let normal = (point - pos).normalize_or_zero();
+                Some(WallContact {
+                    wall,
+                    normal,
+                    point,
+                })
+            }
+        }
+    }
+
+    /// Closest wall to a point inside the domain
+    pub fn nearest_wall(&self, pos: Vec2) -> Option<WallContact> {
+        match self {
+            DomainShape::Box { min, max } => {
+                let candidates = [
+                    (0, Vec2::X, pos.x - min.x),
+                    (1, Vec2::NEG_X, max.x - pos.x),
+                    (2, Vec2::Y, pos.y - min.y),
+                    (3, Vec2::NEG_Y, max.y - pos.y),
+                ];
+                let (wall, normal, dist) =
+                    candidates.into_iter().min_by(|a, b| a.2.total_cmp(&b.2))?;
+                Some(WallContact {
+                    wall,
+                    normal,
+                    point: pos - normal * dist,
+                })
+            }
+            DomainShape::Circle { center, radius } => {
+                let normal = (*center - pos).normalize_or(Vec2::Y);
+                Some(WallContact {
+                    wall: 0,
+                    normal,
+                    point: *center - normal * *radius,
+                })
+            }
+            DomainShape::Polygon { vertices } => {
+                let (wall, point) = closest_polygon_edge(vertices, pos)?;
+                let normal = (pos - point).normalize_or_zero();
+                Some(WallContact {
+                    wall,
+                    normal,
+                    point,
+                })
+            }
+        }
+    }
+
+    /// Closed outline of the domain, used for drawing
+    pub fn outline(&self) -> Vec<Vec2> {
+        match self {
+            DomainShape::Box { min, max } => {
+                vec![*min, Vec2::new(max.x, min.y), *max, Vec2::new(min.x, max.y)]
+            }
+            DomainShape::Circle { center, radius } => (0..64)
+                .map(|i| {
+                    let angle = i as f32 / 64.0 * std::f32::consts::TAU;
+                    *center + Vec2::from_angle(angle) * *radius
+                })
+                .collect(),
+            DomainShape::Polygon { vertices } => vertices.clone(),
+        }
+    }
+}
+
+impl SimDomain {
+    pub fn wall_properties(&self, wall: usize) -> WallProperties {
+        self.walls.get(wall).copied().unwrap_or(self.default_wall)
+    }
+
+    /// Pushes a particle that left the domain back onto the wall and reflects its velocity.
+    pub fn collide(&self, mut pos: Vec2, mut vel: Vec2) -> (Vec2, Vec2) {
+        // A few iterations so corners (two walls crossed at once) are resolved as well
+        for _ in 0..4 {
+            let Some(contact) = self.shape.contact(pos) else {
+                break;
+            };
+            let props = self.wall_properties(contact.wall);
+            pos = contact.point + contact.normal * 1e-4;
+
+            let normal_speed = vel.dot(contact.normal);
+            if normal_speed < 0.0 {
+                let tangential = vel - contact.normal * normal_speed;
+                vel = tangential * (1.0 - props.friction).max(0.0)
+                    - contact.normal * normal_speed * props.restitution;
+            }
+        }
+        (pos, vel)
+    }
+}
+
+fn polygon_contains(vertices: &[Vec2], pos: Vec2) -> bool {
+    let mut inside = false;
+    let mut j = vertices.len().wrapping_sub(1);
+    for (i, a) in vertices.iter().enumerate() {
+        let b = vertices[j];
+        if (a.y > pos.y) != (b.y > pos.y) && pos.x < (b.x - a.x) * (pos.y - a.y) / (b.y - a.y) + a.x
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+pub fn closest_point_on_segment(a: Vec2, b: Vec2, pos: Vec2) -> Vec2 {
+    let ab = b - a;
+    let len_sq = ab.length_squared();
+    if len_sq <= f32::EPSILON {
+        return a;
+    }
+    let t = ((pos - a).dot(ab) / len_sq).clamp(0.0, 1.0);
+    a + ab * t
+}
+
+fn closest_polygon_edge(vertices: &[Vec2], pos: Vec2) -> Option<(usize, Vec2)> {
+    (0..vertices.len())
+        .map(|i| {
+            let a = vertices[i];
+            let b = vertices[(i + 1) % vertices.len()];
+            (i, closest_point_on_segment(a, b, pos))
+        })
+        .min_by(|a, b| {
+            a.1.distance_squared(pos)
+                .total_cmp(&b.1.distance_squared(pos))
+        })
+}
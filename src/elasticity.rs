@@ -0,0 +1,235 @@
+use crate::chunk::EntityLookupChunk;
+use crate::kernel::Kernel;
+use crate::material::{FluidMaterials, MaterialId};
+use crate::particle::{Acceleration, Mass, Particle, PredictedPos, SimParameters};
+use bevy::prelude::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Spring {
+    pub other: Entity,
+    pub rest_length: f32,
+}
+
+/// Springs to the neighbors of an elastic particle. Every spring is stored once, on the particle
+/// with the lower `Entity`.
+#[derive(Component, Default, Clone, Debug)]
+pub struct Springs(pub Vec<Spring>);
+
+/// Creates springs between neighbors of elastic materials, lets their rest lengths yield under
+/// large strain and breaks the ones stretched past the smoothing radius, either right now or
+/// permanently through their rest length (Clavet et al. 2005)
+pub fn update_springs(
+    mut particles: Query<(Entity, &PredictedPos, &MaterialId, &mut Springs), With<Particle>>,
+    read_particles: Query<(&PredictedPos, &MaterialId), With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    materials: Res<FluidMaterials>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    let dt = params.step_dt();
+    let h = kernel.radius();
+    for (entity, predicted, material, mut springs) in particles.iter_mut() {
+        let Some(elasticity) = materials.elasticity(*material) else {
+            springs.0.clear();
+            continue;
+        };
+        let pos = predicted.0;
+
+        springs.0.retain_mut(|spring| {
+            // The other particle may have been despawned since the spring was made
+            let Ok((other_predicted, _)) = read_particles.get(spring.other) else {
+                return false;
+            };
+            let length = pos.distance(other_predicted.0);
+            let tolerance = elasticity.yield_ratio * spring.rest_length;
+            if length > spring.rest_length + tolerance {
+                spring.rest_length +=
+                    elasticity.plasticity * dt * (length - spring.rest_length - tolerance);
+            } else if length < spring.rest_length - tolerance {
+                spring.rest_length -=
+                    elasticity.plasticity * dt * (spring.rest_length - tolerance - length);
+            }
+            spring.rest_length <= h && length <= h
+        });
+
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            if other <= entity || springs.0.iter().any(|spring| spring.other == other) {
+                continue;
+            }
+            let (other_predicted, other_material) = read_particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            if other_material != material {
+                continue;
+            }
+            let dist = pos.distance(other_predicted.0);
+            if dist >= h || dist <= 0.000001 {
+                continue;
+            }
+            springs.0.push(Spring {
+                other,
+                rest_length: dist,
+            });
+        }
+    }
+}
+
+/// Spring force `k (1 - L / h) (L - r)` along the spring, scaled by the reduced mass of the pair
+/// so both ends get equal and opposite forces
+pub fn calc_spring_force(
+    particles: Query<(Entity, &PredictedPos, &Mass, &MaterialId, &Springs), With<Particle>>,
+    mut accelerations: Query<&mut Acceleration, With<Particle>>,
+    materials: Res<FluidMaterials>,
+    kernel: Res<Kernel>,
+) {
+    let h = kernel.radius();
+    let mut forces = Vec::new();
+    for (entity, predicted, mass, material, springs) in particles.iter() {
+        let Some(elasticity) = materials.elasticity(*material) else {
+            continue;
+        };
+        for spring in springs.0.iter() {
+            let Ok((_, other_predicted, other_mass, _, _)) = particles.get(spring.other) else {
+                continue;
+            };
+            let diff = predicted.0 - other_predicted.0;
+            let dist = diff.length();
+            if dist <= 0.000001 {
+                continue;
+            }
+            let reduced_mass = mass.0 * other_mass.0 / (mass.0 + other_mass.0);
+            let magnitude = elasticity.stiffness
+                * reduced_mass
+                * (1.0 - spring.rest_length / h)
+                * (spring.rest_length - dist);
+            let force = diff / dist * magnitude;
+            if force.is_finite() {
+                forces.push((entity, force / mass.0));
+                forces.push((spring.other, -force / other_mass.0));
+            }
+        }
+    }
+    for (entity, spring_acc) in forces {
+        if let Ok(mut acc) = accelerations.get_mut(entity) {
+            acc.0 += spring_acc;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::KernelKind;
+    use crate::material::{Elasticity, FluidMaterial};
+
+    const ELASTIC: MaterialId = MaterialId(1);
+
+    fn world_with(particles: &[(Vec2, MaterialId)]) -> (World, Vec<Entity>) {
+        let mut world = World::new();
+        world.insert_resource(Kernel::new(KernelKind::default(), 1.0));
+        world.insert_resource(EntityLookupChunk::new(1.0));
+        world.init_resource::<SimParameters>();
+        let mut materials = FluidMaterials::default();
+        materials.materials.insert(
+            ELASTIC.0,
+            FluidMaterial {
+                elasticity: Some(Elasticity::default()),
+                ..default()
+            },
+        );
+        world.insert_resource(materials);
+
+        let entities = particles
+            .iter()
+            .map(|&(pos, material)| {
+                let chunk_position = world
+                    .resource::<EntityLookupChunk>()
+                    .chunk_position(pos.x, pos.y);
+                world
+                    .spawn((
+                        Particle,
+                        PredictedPos(pos),
+                        material,
+                        Springs::default(),
+                        chunk_position,
+                    ))
+                    .id()
+            })
+            .collect();
+        (world, entities)
+    }
+
+    fn update(world: &mut World) {
+        let mut schedule = Schedule::default();
+        schedule.add_systems(update_springs);
+        schedule.run(world);
+    }
+
+    fn springs(world: &World, entity: Entity) -> Vec<Spring> {
+        world.get::<Springs>(entity).unwrap().0.clone()
+    }
+
+    fn move_to(world: &mut World, entity: Entity, pos: Vec2) {
+        world.get_mut::<PredictedPos>(entity).unwrap().0 = pos;
+    }
+
+    #[test]
+    fn springs_connect_elastic_neighbors() {
+        let (mut world, entities) = world_with(&[
+            (Vec2::ZERO, ELASTIC),
+            (Vec2::new(0.5, 0.0), ELASTIC),
+            (Vec2::new(0.0, 0.5), MaterialId(0)),
+            (Vec2::new(0.0, -1.5), ELASTIC),
+        ]);
+        update(&mut world);
+
+        // Stored once, on the lower entity, and only within the radius and the material
+        let first = springs(&world, entities[0]);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].other, entities[1]);
+        assert!((first[0].rest_length - 0.5).abs() < 1e-6);
+        for &entity in &entities[1..] {
+            assert!(springs(&world, entity).is_empty());
+        }
+
+        // Running again does not duplicate it
+        update(&mut world);
+        assert_eq!(springs(&world, entities[0]).len(), 1);
+    }
+
+    #[test]
+    fn rest_length_yields_beyond_the_tolerance() {
+        let (mut world, entities) = world_with(&[(Vec2::ZERO, ELASTIC), (Vec2::X * 0.5, ELASTIC)]);
+        update(&mut world);
+
+        // Within the yield ratio the spring stays purely elastic
+        move_to(&mut world, entities[1], Vec2::X * 0.52);
+        update(&mut world);
+        assert!((springs(&world, entities[0])[0].rest_length - 0.5).abs() < 1e-6);
+
+        move_to(&mut world, entities[1], Vec2::X * 0.8);
+        update(&mut world);
+        let stretched = springs(&world, entities[0])[0].rest_length;
+        assert!(
+            stretched > 0.5 && stretched < 0.8,
+            "rest length {stretched}"
+        );
+
+        move_to(&mut world, entities[1], Vec2::X * 0.2);
+        update(&mut world);
+        let compressed = springs(&world, entities[0])[0].rest_length;
+        assert!(compressed < stretched, "rest length {compressed}");
+    }
+
+    #[test]
+    fn springs_break_past_the_radius() {
+        let (mut world, entities) = world_with(&[(Vec2::ZERO, ELASTIC), (Vec2::X * 0.5, ELASTIC)]);
+        update(&mut world);
+        assert_eq!(springs(&world, entities[0]).len(), 1);
+
+        move_to(&mut world, entities[1], Vec2::X * 1.2);
+        update(&mut world);
+        assert!(springs(&world, entities[0]).is_empty());
+    }
+}
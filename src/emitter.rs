@@ -0,0 +1,191 @@
+use crate::basic_assets::{MaterialColorDatabase, MeshShapeDatabase, SimAssetId};
+use crate::chunk::EntityLookupChunk;
+use crate::material::{FluidMaterials, MaterialId};
+use crate::obstacle::ObstacleShape;
+use crate::particle::{
+    Particle, ParticleBundle, ParticleVisualBundle, PredictedPos, SimParameters,
+};
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use rand::Rng;
+
+/// Area new particles appear in, in the local space of the emitter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmitterShape {
+    Point,
+    /// Segment along the local x axis, like a sprinkler pipe
+    Line {
+        half_length: f32,
+    },
+    Circle {
+        radius: f32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmissionMode {
+    /// `rate` particles per second for as long as the emitter exists
+    Continuous,
+    /// `count` particles at once. Repeats every `interval` seconds if given, otherwise the emitter
+    /// removes itself after the first burst.
+    Burst { count: u32, interval: Option<f32> },
+}
+
+/// Spawns particles of one material, placed inside the domain by the user
+#[derive(Component, Clone, Debug)]
+#[require(Transform)]
+pub struct FluidEmitter {
+    /// Particles per second of a continuous emitter
+    pub rate: f32,
+    /// Initial velocity in the local space of the emitter
+    pub velocity: Vec2,
+    /// Largest angle in radians the velocity of a particle randomly deviates by
+    pub spread: f32,
+    pub material: MaterialId,
+    pub shape: EmitterShape,
+    pub mode: EmissionMode,
+    /// Particles owed from previous ticks for continuous emitters, seconds until the next burst
+    /// for bursts
+    pub accumulator: f32,
+}
+
+impl Default for FluidEmitter {
+    fn default() -> Self {
+        Self {
+            rate: 20.0,
+            velocity: Vec2::ZERO,
+            spread: 0.0,
+            material: MaterialId::default(),
+            shape: EmitterShape::Point,
+            mode: EmissionMode::Continuous,
+            accumulator: 0.0,
+        }
+    }
+}
+
+impl FluidEmitter {
+    /// Number of particles to emit this tick
+    fn advance(&mut self, dt: f32) -> u32 {
+        match self.mode {
+            EmissionMode::Continuous => {
+                self.accumulator += self.rate.max(0.0) * dt;
+                let count = self.accumulator.floor();
+                self.accumulator -= count;
+                count as u32
+            }
+            EmissionMode::Burst { count, interval } => {
+                self.accumulator -= dt;
+                if self.accumulator > 0.0 {
+                    return 0;
+                }
+                self.accumulator += interval.unwrap_or(0.0).max(dt);
+                count
+            }
+        }
+    }
+
+    fn is_spent(&self) -> bool {
+        matches!(self.mode, EmissionMode::Burst { interval: None, .. })
+    }
+
+    /// Random spawn position in local space
+    fn sample_position(&self, rng: &mut impl Rng, spacing: f32) -> Vec2 {
+        match self.shape {
+            // Keeps consecutive particles from sitting exactly on top of each other
+            EmitterShape::Point => {
+                Vec2::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5) * 0.2 * spacing
+            }
+            EmitterShape::Line { half_length } => {
+                Vec2::new((rng.gen::<f32>() * 2.0 - 1.0) * half_length, 0.0)
+            }
+            EmitterShape::Circle { radius } => {
+                Vec2::from_angle(rng.gen::<f32>() * std::f32::consts::TAU)
+                    * radius
+                    * rng.gen::<f32>().sqrt()
+            }
+        }
+    }
+}
+
+/// Removes every particle that enters its shape
+#[derive(Component, Clone, Debug)]
+#[require(Transform)]
+pub struct FluidSink {
+    pub shape: ObstacleShape,
+}
+
+pub fn emit_particles(
+    mut commands: Commands,
+    mut emitters: Query<(Entity, &mut FluidEmitter, &Transform)>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    meshes: Res<MeshShapeDatabase>,
+    colors: Res<MaterialColorDatabase>,
+    materials: Res<FluidMaterials>,
+    params: Res<SimParameters>,
+) {
+    let Some(mesh_handle) = meshes.handles.get(&SimAssetId::Particle) else {
+        return;
+    };
+    let spacing = entity_lookup_chunk.cell_size();
+    let mut rng = rand::thread_rng();
+
+    for (emitter_entity, mut emitter, transform) in emitters.iter_mut() {
+        let count = emitter.advance(params.dt);
+        if count > 0 && emitter.is_spent() {
+            commands.entity(emitter_entity).remove::<FluidEmitter>();
+        }
+        let Some(color_handle) = colors.handles.get(&emitter.material.0) else {
+            continue;
+        };
+        // Same mass as the particles of the initial lattice
+        let mass = materials.rest_density(emitter.material) * spacing * spacing;
+
+        for _ in 0..count {
+            let local = emitter.sample_position(&mut rng, spacing);
+            let position = transform.transform_point(local.extend(0.0)).truncate();
+            let deviation = (rng.gen::<f32>() * 2.0 - 1.0) * emitter.spread;
+            let direction = Vec2::from_angle(deviation).rotate(emitter.velocity);
+            let velocity = (transform.rotation * direction.extend(0.0)).truncate();
+
+            let chunk_position = entity_lookup_chunk.chunk_position(position.x, position.y);
+            let particle_bundle = ParticleBundle::new(
+                position,
+                velocity,
+                mass,
+                emitter.material,
+                chunk_position,
+                ParticleVisualBundle {
+                    mesh: Mesh2d(mesh_handle.clone()),
+                    mesh_material: MeshMaterial2d(color_handle.clone()),
+                },
+            );
+            commands.spawn(particle_bundle);
+        }
+    }
+}
+
+pub fn absorb_particles(
+    mut commands: Commands,
+    sinks: Query<(&FluidSink, &Transform)>,
+    particles: Query<&PredictedPos, With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+) {
+    // Sinks may overlap, but every particle only gets despawned once
+    let mut absorbed = HashSet::new();
+    for (sink, transform) in sinks.iter() {
+        let center = transform.translation.truncate();
+        let reach = Vec2::splat(sink.shape.bounding_radius() * transform.scale.x.abs());
+        for entity in entity_lookup_chunk.get_area_entities(center - reach, center + reach) {
+            let Ok(predicted) = particles.get(entity) else {
+                continue;
+            };
+            if sink.shape.probe(transform, predicted.0).distance > 0.0 {
+                continue;
+            }
+            // The `ChunkPosition` hook unregisters the particle once the despawn is applied
+            if absorbed.insert(entity) {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
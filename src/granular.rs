@@ -0,0 +1,260 @@
+use crate::chunk::EntityLookupChunk;
+use crate::kernel::Kernel;
+use crate::material::{FluidMaterials, MaterialId};
+use crate::particle::{
+    Acceleration, LocalMassDensity, Mass, Particle, PredictedPos, Pressure, SimParameters, Velocity,
+};
+use bevy::prelude::*;
+
+/// Share of liquid among the neighbors of a grain, from 0 for dry to 1 for fully submerged sand.
+/// Always 0 for particles that are not granular.
+#[derive(Component, Default, Clone, Debug)]
+pub struct Wetness(pub f32);
+
+pub fn calc_wetness(
+    mut particles: Query<(&PredictedPos, &MaterialId, &mut Wetness), With<Particle>>,
+    read_particles: Query<(&PredictedPos, &Mass, &LocalMassDensity, &MaterialId), With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    materials: Res<FluidMaterials>,
+    kernel: Res<Kernel>,
+) {
+    for (predicted, material, mut wetness) in particles.iter_mut() {
+        if materials.granular(*material).is_none() {
+            wetness.0 = 0.0;
+            continue;
+        }
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        let mut liquid = 0.0;
+        let mut total = 0.0;
+        for entity in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            let (other_predicted, other_mass, other_density, other_material) = read_particles
+                .get(entity)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let dist = pos.distance(other_predicted.0);
+            if dist >= kernel.radius() {
+                continue;
+            }
+            // The grain itself counts as dry, so a lone grain is not wet
+            let weight = other_mass.0 / other_density.0 * kernel.value(dist);
+            total += weight;
+            if materials.granular(*other_material).is_none() {
+                liquid += weight;
+            }
+        }
+        let fraction = liquid / total;
+        wetness.0 = if fraction.is_finite() {
+            fraction.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+    }
+}
+
+/// Coulomb friction between touching grains. The normal force of a pair is the larger of the
+/// pressure force between them and the contact force that stops them closing in on each other
+/// within the step. The pressure is the solver's own (see [`Pressure`]), but it only builds up
+/// once grains overlap, so grains that just came into contact still rub. Friction opposes the
+/// tangential sliding with at most `μ` times the normal force, so a pile stands as long as its
+/// slope stays below the angle of repose.
+pub fn calc_granular_friction(
+    particles: Query<(Entity, &PredictedPos, &Velocity, &MaterialId, &Wetness), With<Particle>>,
+    pressures: Query<(&Mass, &LocalMassDensity, &Pressure), With<Particle>>,
+    mut accelerations: Query<&mut Acceleration, With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    materials: Res<FluidMaterials>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    let dt = params.step_dt();
+    if dt <= 0.0 {
+        return;
+    }
+
+    let mut forces = Vec::new();
+    for (entity, predicted, vel, material, wetness) in particles.iter() {
+        let Some(granular) = materials.granular(*material) else {
+            continue;
+        };
+        let (mass, density, pressure) = pressures
+            .get(entity)
+            .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+        let own_term = pressure.0.max(0.0) / (density.0 * density.0);
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            // Every pair once, the forces get applied to both grains
+            if other <= entity {
+                continue;
+            }
+            let (_, other_predicted, other_vel, other_material, other_wetness) = particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let Some(other_granular) = materials.granular(*other_material) else {
+                continue;
+            };
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let (other_mass, other_density, other_pressure) = pressures
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let other_term = other_pressure.0.max(0.0) / (other_density.0 * other_density.0);
+            let reduced_mass = mass.0 * other_mass.0 / (mass.0 + other_mass.0);
+
+            let normal = diff / dist;
+            let relative = vel.0 - other_vel.0;
+            let closing_speed = (-relative.dot(normal)).max(0.0);
+            let pressure_force =
+                mass.0 * other_mass.0 * (own_term + other_term) * kernel.gradient(dist).abs();
+            let normal_force = pressure_force.max(reduced_mass * closing_speed / dt);
+
+            let sliding = relative - normal * relative.dot(normal);
+            let speed = sliding.length();
+            if speed <= 0.000001 {
+                continue;
+            }
+            let friction =
+                (granular.friction(wetness.0) + other_granular.friction(other_wetness.0)) * 0.5;
+            // Static friction can stop the sliding, but never reverse it
+            let stopping_force = reduced_mass * speed / dt;
+            let force = sliding / speed * (friction * normal_force).min(stopping_force);
+            if force.is_finite() {
+                forces.push((entity, -force / mass.0));
+                forces.push((other, force / other_mass.0));
+            }
+        }
+    }
+    for (entity, friction_acc) in forces {
+        if let Ok(mut acc) = accelerations.get_mut(entity) {
+            acc.0 += friction_acc;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::KernelKind;
+    use crate::material::{FluidMaterial, Granular};
+    use crate::particle::{
+        calc_local_mass_density, calc_pressure, calc_pressure_force, update_chunk_positions,
+        NearDensity,
+    };
+    use crate::thermal::{Temperature, ThermalParameters};
+
+    const SAND: MaterialId = MaterialId(1);
+
+    /// Semi-implicit Euler on a rough floor at `y = 0` that stops everything touching it
+    fn advance(
+        mut particles: Query<(&mut PredictedPos, &mut Velocity, &Acceleration), With<Particle>>,
+        params: Res<SimParameters>,
+    ) {
+        let dt = params.step_dt();
+        for (mut predicted, mut vel, acc) in particles.iter_mut() {
+            vel.0 += acc.0 * dt;
+            predicted.0 += vel.0 * dt;
+            if predicted.0.y < 0.0 {
+                predicted.0.y = 0.0;
+                vel.0 = Vec2::ZERO;
+            }
+        }
+    }
+
+    /// Lets a pile with 60° flanks settle for ten seconds, returns its height and half width
+    fn settle_pile(material: MaterialId) -> (f32, f32) {
+        let mut world = World::new();
+        world.insert_resource(Kernel::new(KernelKind::default(), 1.0));
+        world.insert_resource(EntityLookupChunk::new(1.0));
+        let mut params = SimParameters::default();
+        params.gravity = 2.0;
+        world.insert_resource(params);
+        world.init_resource::<ThermalParameters>();
+        let mut materials = FluidMaterials::default();
+        materials.materials.insert(
+            SAND.0,
+            FluidMaterial {
+                granular: Some(Granular::default()),
+                ..default()
+            },
+        );
+        world.insert_resource(materials);
+
+        let spacing = 0.9;
+        let base = 11;
+        for row in 0..base {
+            for i in 0..base - row {
+                let pos = Vec2::new(
+                    (i as f32 + row as f32 * 0.5) * spacing,
+                    row as f32 * spacing * 0.866,
+                );
+                let chunk_position = world
+                    .resource::<EntityLookupChunk>()
+                    .chunk_position(pos.x, pos.y);
+                world.spawn((
+                    Particle,
+                    PredictedPos(pos),
+                    Velocity::default(),
+                    Acceleration::default(),
+                    Mass(1.0),
+                    LocalMassDensity::default(),
+                    NearDensity::default(),
+                    Pressure::default(),
+                    Temperature::default(),
+                    material,
+                    Wetness::default(),
+                    chunk_position,
+                ));
+            }
+        }
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(
+            (
+                update_chunk_positions,
+                calc_local_mass_density,
+                calc_pressure,
+                calc_pressure_force,
+                calc_wetness,
+                calc_granular_friction,
+                advance,
+            )
+                .chain(),
+        );
+        for _ in 0..600 {
+            schedule.run(&mut world);
+        }
+
+        let positions: Vec<Vec2> = world
+            .query::<&PredictedPos>()
+            .iter(&world)
+            .map(|predicted| predicted.0)
+            .collect();
+        let height = positions.iter().fold(0.0f32, |max, pos| max.max(pos.y));
+        let (min, max) = positions
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), pos| {
+                (min.min(pos.x), max.max(pos.x))
+            });
+        (height, (max - min) * 0.5)
+    }
+
+    #[test]
+    fn sand_pile_keeps_a_slope() {
+        let (height, half_width) = settle_pile(SAND);
+        let slope = (height / half_width).atan().to_degrees();
+        assert!(
+            slope > 25.0,
+            "the sand pile slumped to {slope}° ({height} high, {half_width} wide)"
+        );
+
+        // The same pile without friction runs flat
+        let (height, half_width) = settle_pile(MaterialId(0));
+        let slope = (height / half_width).atan().to_degrees();
+        assert!(slope < 15.0, "the liquid pile still stands at {slope}°");
+    }
+}
@@ -0,0 +1,139 @@
+use crate::particle::{Mass, Particle, SimParameters, Velocity};
+use bevy::prelude::*;
+
+/// Time integration scheme of the particles, chosen on `ParticlePlugin`. Every step is split in
+/// three parts around the force evaluation: [`Integrator::predict`] picks the positions the forces
+/// are evaluated at, [`Integrator::kick`] applies the new acceleration and [`Integrator::drift`]
+/// gives the final position before collisions.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Integrator {
+    /// Kick with the new acceleration, then drift with the new velocity
+    #[default]
+    SemiImplicitEuler,
+    /// Kick-drift-kick: half a kick with the previous acceleration, a full drift, and the other
+    /// half kick once the forces at the new positions are known
+    Leapfrog,
+    /// Drift using the previous acceleration, then kick with the mean of the previous and the
+    /// new acceleration. Forces see the velocity from the start of the step.
+    VelocityVerlet,
+}
+
+impl Integrator {
+    pub const ALL: [Integrator; 3] = [
+        Integrator::SemiImplicitEuler,
+        Integrator::Leapfrog,
+        Integrator::VelocityVerlet,
+    ];
+
+    /// Position the forces get evaluated at and the velocity they see during the step
+    pub fn predict(self, pos: Vec2, vel: Vec2, previous_acc: Vec2, dt: f32) -> (Vec2, Vec2) {
+        match self {
+            Integrator::SemiImplicitEuler => (pos, vel),
+            Integrator::Leapfrog => {
+                let half = vel + previous_acc * 0.5 * dt;
+                (pos + half * dt, half)
+            }
+            Integrator::VelocityVerlet => (pos + vel * dt + previous_acc * 0.5 * dt * dt, vel),
+        }
+    }
+
+    /// Velocity once the acceleration at the predicted positions is known
+    pub fn kick(self, vel: Vec2, previous_acc: Vec2, acc: Vec2, dt: f32) -> Vec2 {
+        match self {
+            Integrator::SemiImplicitEuler => vel + acc * dt,
+            Integrator::Leapfrog => vel + acc * 0.5 * dt,
+            Integrator::VelocityVerlet => vel + (previous_acc + acc) * 0.5 * dt,
+        }
+    }
+
+    /// Position at the end of the step, before collisions are resolved
+    pub fn drift(self, pos: Vec2, predicted: Vec2, vel: Vec2, dt: f32) -> Vec2 {
+        match self {
+            Integrator::SemiImplicitEuler => pos + vel * dt,
+            Integrator::Leapfrog | Integrator::VelocityVerlet => predicted,
+        }
+    }
+}
+
+/// Mechanical energy of all particles, measured after every fixed tick. Pressure and the other
+/// internal forces store energy too, so only the drift over time is meaningful.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct SimEnergy {
+    pub kinetic: f32,
+    pub potential: f32,
+    /// Total energy the drift is measured against, taken on the first tick after a reset
+    pub reference: Option<f32>,
+}
+
+impl SimEnergy {
+    pub fn total(&self) -> f32 {
+        self.kinetic + self.potential
+    }
+
+    /// Relative change of the total energy since the reference was taken
+    pub fn drift(&self) -> f32 {
+        match self.reference {
+            Some(reference) if reference.abs() > f32::EPSILON => {
+                (self.total() - reference) / reference.abs()
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+pub fn measure_energy(
+    particles: Query<(&Transform, &Velocity, &Mass), With<Particle>>,
+    params: Res<SimParameters>,
+    mut energy: ResMut<SimEnergy>,
+) {
+    let (kinetic, potential) = particles.iter().fold(
+        (0.0, 0.0),
+        |(kinetic, potential), (transform, vel, mass)| {
+            (
+                kinetic + 0.5 * mass.0 * vel.0.length_squared(),
+                potential + mass.0 * params.gravity * transform.translation.y,
+            )
+        },
+    );
+    energy.kinetic = kinetic;
+    energy.potential = potential;
+    if energy.reference.is_none() {
+        energy.reference = Some(energy.total());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Largest relative energy error of a unit harmonic oscillator, `a = -x`
+    fn oscillator_energy_error(integrator: Integrator, dt: f32, steps: usize) -> f32 {
+        let energy = |pos: Vec2, vel: Vec2| 0.5 * (pos.length_squared() + vel.length_squared());
+        let (mut pos, mut vel) = (Vec2::X, Vec2::ZERO);
+        let mut acc = -pos;
+        let initial = energy(pos, vel);
+
+        let mut max_error = 0.0f32;
+        for _ in 0..steps {
+            let (predicted, predicted_vel) = integrator.predict(pos, vel, acc, dt);
+            let new_acc = -predicted;
+            vel = integrator.kick(predicted_vel, acc, new_acc, dt);
+            pos = integrator.drift(pos, predicted, vel, dt);
+            acc = new_acc;
+            max_error = max_error.max((energy(pos, vel) - initial).abs() / initial);
+        }
+        max_error
+    }
+
+    #[test]
+    fn second_order_schemes_drift_less_than_euler() {
+        let euler = oscillator_energy_error(Integrator::SemiImplicitEuler, 0.1, 1000);
+        for integrator in [Integrator::Leapfrog, Integrator::VelocityVerlet] {
+            let error = oscillator_energy_error(integrator, 0.1, 1000);
+            assert!(
+                error * 5.0 < euler,
+                "{integrator:?} drifts by {error}, semi-implicit Euler by {euler}"
+            );
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use bevy::prelude::*;
+use std::f32::consts::PI;
+
+/// Radially symmetric SPH smoothing kernel in 2D, normalized to integrate to 1 over its support.
+pub trait SmoothingKernel: Send + Sync + 'static {
+    /// Support radius, the kernel is zero beyond it
+    fn radius(&self) -> f32;
+    /// Constant factor that makes the kernel integrate to 1 over the disc of `radius`
+    fn normalization(&self) -> f32;
+    fn value(&self, distance: f32) -> f32;
+    /// Radial derivative dW/dr; negative since every kernel falls off with distance
+    fn gradient(&self, distance: f32) -> f32;
+    /// 2D Laplacian W'' + W'/r
+    #[allow(dead_code)]
+    fn laplacian(&self, distance: f32) -> f32;
+
+    /// Kernel gradient with respect to the first particle, `diff` pointing from the second to
+    /// the first one
+    fn gradient_at(&self, diff: Vec2) -> Vec2 {
+        let dist = diff.length();
+        if dist <= f32::EPSILON {
+            return Vec2::ZERO;
+        }
+        diff / dist * self.gradient(dist)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KernelKind {
+    Poly6,
+    /// Closest to the original `(r - d)^3` falloff
+    #[default]
+    Spiky,
+    CubicSpline,
+    WendlandC2,
+}
+
+impl KernelKind {
+    pub const ALL: [KernelKind; 4] = [
+        KernelKind::Poly6,
+        KernelKind::Spiky,
+        KernelKind::CubicSpline,
+        KernelKind::WendlandC2,
+    ];
+
+    pub fn build(self, radius: f32) -> Box<dyn SmoothingKernel> {
+        match self {
+            KernelKind::Poly6 => Box::new(Poly6 { radius }),
+            KernelKind::Spiky => Box::new(Spiky { radius }),
+            KernelKind::CubicSpline => Box::new(CubicSpline { radius }),
+            KernelKind::WendlandC2 => Box::new(WendlandC2 { radius }),
+        }
+    }
+}
+
+/// The kernel every density and force evaluation goes through, chosen on `ParticlePlugin`
+#[derive(Resource)]
+pub struct Kernel {
+    pub kind: KernelKind,
+    pub function: Box<dyn SmoothingKernel>,
+}
+
+impl Kernel {
+    pub fn new(kind: KernelKind, radius: f32) -> Self {
+        Self {
+            kind,
+            function: kind.build(radius),
+        }
+    }
+
+    /// Kernel of the near density, `(1 - r / h)³` as in Clavet et al. 2005. That is the spiky
+    /// kernel, whichever kernel is chosen for everything else.
+    pub fn near(&self) -> Spiky {
+        Spiky {
+            radius: self.radius(),
+        }
+    }
+}
+
+impl std::ops::Deref for Kernel {
+    type Target = dyn SmoothingKernel;
+
+    fn deref(&self) -> &Self::Target {
+        self.function.as_ref()
+    }
+}
+
+/// Number density `Σ W` a particle sees inside a square lattice with the given spacing, its own
+/// contribution included. Used as the rest state by the solvers that enforce incompressibility.
+pub fn lattice_density(kernel: &dyn SmoothingKernel, spacing: f32) -> f32 {
+    let reach = (kernel.radius() / spacing).ceil() as i32;
+    let mut density = 0.0;
+    for x in -reach..=reach {
+        for y in -reach..=reach {
+            let offset = Vec2::new(x as f32, y as f32) * spacing;
+            density += kernel.value(offset.length());
+        }
+    }
+    density
+}
+
+/// `(h² - r²)³`, smooth at the center and cheap since it only needs `r²`
+#[derive(Clone, Copy, Debug)]
+pub struct Poly6 {
+    pub radius: f32,
+}
+
+impl SmoothingKernel for Poly6 {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn normalization(&self) -> f32 {
+        4.0 / (PI * self.radius.powi(8))
+    }
+
+    fn value(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        let x = self.radius * self.radius - distance * distance;
+        self.normalization() * x * x * x
+    }
+
+    fn gradient(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        let x = self.radius * self.radius - distance * distance;
+        -6.0 * self.normalization() * distance * x * x
+    }
+
+    fn laplacian(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        let h_sq = self.radius * self.radius;
+        let r_sq = distance * distance;
+        -12.0 * self.normalization() * (h_sq - r_sq) * (h_sq - 3.0 * r_sq)
+    }
+}
+
+/// `(h - r)³`, its gradient does not vanish at the center so close particles still repel
+#[derive(Clone, Copy, Debug)]
+pub struct Spiky {
+    pub radius: f32,
+}
+
+impl SmoothingKernel for Spiky {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn normalization(&self) -> f32 {
+        10.0 / (PI * self.radius.powi(5))
+    }
+
+    fn value(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        self.normalization() * (self.radius - distance).powi(3)
+    }
+
+    fn gradient(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        -3.0 * self.normalization() * (self.radius - distance).powi(2)
+    }
+
+    fn laplacian(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) || distance == 0.0 {
+            return 0.0;
+        }
+        let x = self.radius - distance;
+        self.normalization() * (6.0 * x - 3.0 * x * x / distance)
+    }
+}
+
+/// Monaghan's M4 cubic B-spline with its support scaled to `radius`
+#[derive(Clone, Copy, Debug)]
+pub struct CubicSpline {
+    pub radius: f32,
+}
+
+impl SmoothingKernel for CubicSpline {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn normalization(&self) -> f32 {
+        40.0 / (7.0 * PI * self.radius * self.radius)
+    }
+
+    fn value(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        let q = distance / self.radius;
+        let shape = if q <= 0.5 {
+            6.0 * (q * q * q - q * q) + 1.0
+        } else {
+            2.0 * (1.0 - q).powi(3)
+        };
+        self.normalization() * shape
+    }
+
+    fn gradient(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        let q = distance / self.radius;
+        let shape = if q <= 0.5 {
+            18.0 * q * q - 12.0 * q
+        } else {
+            -6.0 * (1.0 - q).powi(2)
+        };
+        self.normalization() * shape / self.radius
+    }
+
+    fn laplacian(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) || distance == 0.0 {
+            return 0.0;
+        }
+        let q = distance / self.radius;
+        let (first, second) = if q <= 0.5 {
+            (18.0 * q * q - 12.0 * q, 36.0 * q - 12.0)
+        } else {
+            (-6.0 * (1.0 - q).powi(2), 12.0 * (1.0 - q))
+        };
+        self.normalization() * (second + first / q) / (self.radius * self.radius)
+    }
+}
+
+/// Wendland C2, positive definite and resistant to particle pairing
+#[derive(Clone, Copy, Debug)]
+pub struct WendlandC2 {
+    pub radius: f32,
+}
+
+impl SmoothingKernel for WendlandC2 {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn normalization(&self) -> f32 {
+        7.0 / (PI * self.radius * self.radius)
+    }
+
+    fn value(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        let q = distance / self.radius;
+        self.normalization() * (1.0 - q).powi(4) * (1.0 + 4.0 * q)
+    }
+
+    fn gradient(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        let q = distance / self.radius;
+        -20.0 * self.normalization() * q * (1.0 - q).powi(3) / self.radius
+    }
+
+    fn laplacian(&self, distance: f32) -> f32 {
+        if !(0.0..self.radius).contains(&distance) {
+            return 0.0;
+        }
+        let q = distance / self.radius;
+        -20.0 * self.normalization() * (1.0 - q).powi(2) * (2.0 - 5.0 * q)
+            / (self.radius * self.radius)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Midpoint rule over rings of the support disc
+    fn integrate(kernel: &dyn SmoothingKernel) -> f32 {
+        const STEPS: usize = 10_000;
+        let dr = kernel.radius() / STEPS as f32;
+        (0..STEPS)
+            .map(|i| {
+                let r = (i as f32 + 0.5) * dr;
+                kernel.value(r) * 2.0 * PI * r * dr
+            })
+            .sum()
+    }
+
+    #[test]
+    fn kernels_integrate_to_one() {
+        for kind in KernelKind::ALL {
+            for radius in [0.5, 1.0, 2.5] {
+                let integral = integrate(kind.build(radius).as_ref());
+                assert!(
+                    (integral - 1.0).abs() < 1e-3,
+                    "{kind:?} with radius {radius} integrates to {integral}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lattice_density_approaches_continuum() {
+        // With many neighbors inside the support the lattice sum approaches `1 / spacing²`
+        for kind in KernelKind::ALL {
+            let density = lattice_density(kind.build(4.0).as_ref(), 0.25);
+            assert!(
+                (density * 0.25 * 0.25 - 1.0).abs() < 2e-2,
+                "{kind:?} lattice density is {density}"
+            );
+        }
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        const EPS: f32 = 1e-3;
+        for kind in KernelKind::ALL {
+            let kernel = kind.build(1.0);
+            for r in [0.1, 0.3, 0.6, 0.9] {
+                let numeric = (kernel.value(r + EPS) - kernel.value(r - EPS)) / (2.0 * EPS);
+                let analytic = kernel.gradient(r);
+                assert!(
+                    (numeric - analytic).abs() < 1e-2 * analytic.abs().max(1.0),
+                    "{kind:?} at {r}: {analytic} vs {numeric}"
+                );
+            }
+        }
+    }
+}
@@ -3,8 +3,25 @@ mod particle;
 mod camera;
 mod basic_assets;
 mod debug;
+mod domain;
+mod obstacle;
+mod rigid_body;
+mod material;
+mod viscosity;
+mod surface_tension;
+mod kernel;
+mod integrator;
+mod solver;
+mod pbf;
+mod pcisph;
+mod dfsph;
+mod vorticity;
+mod thermal;
+mod elasticity;
+mod granular;
+mod emitter;
 
-use bevy::diagnostic::{DiagnosticsPlugin, LogDiagnosticsPlugin};
+use bevy::diagnostic::LogDiagnosticsPlugin;
 use bevy::prelude::*;
 use crate::camera::CameraPlugin;
 use crate::particle::ParticlePlugin;
@@ -13,6 +30,6 @@ fn main() {
     App::new()
         .add_plugins((DefaultPlugins, LogDiagnosticsPlugin::default()))
         .add_plugins(CameraPlugin)
-        .add_plugins(ParticlePlugin)
+        .add_plugins(ParticlePlugin::default())
         .run();
 }
@@ -0,0 +1,402 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+/// Key of the particle's material in `FluidMaterials`, shared with `MaterialColorDatabase`
+#[derive(Component, Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MaterialId(pub usize);
+
+/// Key of the material of a solid (obstacle or rigid body) in the adhesion table.
+/// Solids without one use material 0, the same as domain walls by default.
+#[derive(Component, Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SolidMaterialId(pub usize);
+
+/// Shape of the pressure law
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PressureModel {
+    /// Ideal gas `p = k (ρ - ρ0)`, soft but cheap to keep stable
+    #[default]
+    Linear,
+    /// Tait `p = k ρ0 / γ ((ρ / ρ0)^γ - 1)` for weakly-compressible liquids. Scaled so it matches
+    /// the linear model for small compression, but stiffens quickly beyond that.
+    Tait,
+}
+
+impl PressureModel {
+    pub const ALL: [PressureModel; 2] = [PressureModel::Linear, PressureModel::Tait];
+}
+
+/// How the pressure of a particle follows from its mass density
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EquationOfState {
+    pub model: PressureModel,
+    /// SPH mass density `m_i Σ W_ij` the pressure vanishes at. `None` uses the density of the
+    /// spawn lattice for the particle's mass, so every particle is at rest in its initial
+    /// arrangement. Unlike [`FluidMaterial::rest_density`] this is compared directly against the
+    /// kernel sum, including its discretization error.
+    pub rest_density: Option<f32>,
+    /// Stiffness `k`, the pressure per unit of density error around the rest density
+    pub stiffness: f32,
+    /// Exponent `γ` of the Tait equation
+    pub exponent: i32,
+    /// Drop negative pressure, which would pull the particles at the free surface into clumps
+    pub clamp_negative: bool,
+}
+
+impl Default for EquationOfState {
+    fn default() -> Self {
+        Self {
+            model: PressureModel::Linear,
+            rest_density: None,
+            stiffness: 360.0,
+            exponent: 7,
+            clamp_negative: true,
+        }
+    }
+}
+
+impl EquationOfState {
+    /// Pressure at `density`; `lattice_density` is the rest density used without an explicit one
+    pub fn pressure(&self, density: f32, lattice_density: f32) -> f32 {
+        let rest_density = self.rest_density.unwrap_or(lattice_density);
+        let pressure = match self.model {
+            PressureModel::Linear => self.stiffness * (density - rest_density),
+            PressureModel::Tait => {
+                if rest_density <= 0.0 || self.exponent == 0 {
+                    return 0.0;
+                }
+                let exponent = self.exponent as f32;
+                self.stiffness * rest_density / exponent
+                    * ((density / rest_density).powi(self.exponent) - 1.0)
+            }
+        };
+        if pressure.is_nan() {
+            0.0
+        } else if self.clamp_negative {
+            pressure.max(0.0)
+        } else {
+            pressure
+        }
+    }
+}
+
+/// Upper bound of the effective viscosity. Yield stress fluids at rest and shear-thinning ones
+/// at very low shear rates would otherwise need arbitrarily small steps.
+pub const MAX_VISCOSITY: f32 = 60.0;
+
+/// Keeps the shear rate models finite for fluid at rest
+const MIN_SHEAR_RATE: f32 = 1e-3;
+
+/// How the viscosity of a material depends on the local shear rate `γ̇`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Rheology {
+    /// Constant viscosity, the material's `viscosity` or the global default
+    #[default]
+    Newtonian,
+    /// `μ = K γ̇^(n - 1)`: shear-thinning like paint for `n < 1`, shear-thickening like
+    /// cornstarch for `n > 1`
+    PowerLaw { consistency: f32, index: f32 },
+    /// `μ = μ∞ + (μ0 - μ∞) / (1 + (λ γ̇)^m)`, shear-thinning with plateaus at both ends
+    Cross {
+        zero_shear: f32,
+        infinite_shear: f32,
+        time_constant: f32,
+        exponent: f32,
+    },
+    /// `μ = μp + τy / γ̇`: barely flows until the stress exceeds the yield stress, like mud or
+    /// ketchup. The model is regularized rather than a true yield: the viscosity is capped at
+    /// [`MAX_VISCOSITY`], so below the yield stress the material creeps slowly instead of
+    /// standing still.
+    Bingham {
+        plastic_viscosity: f32,
+        yield_stress: f32,
+    },
+}
+
+impl Rheology {
+    /// One typical setup of every model
+    pub const ALL: [Rheology; 5] = [
+        Rheology::Newtonian,
+        Rheology::PowerLaw {
+            consistency: 6.0,
+            index: 0.5,
+        },
+        Rheology::PowerLaw {
+            consistency: 0.5,
+            index: 1.5,
+        },
+        Rheology::Cross {
+            zero_shear: 24.0,
+            infinite_shear: 1.0,
+            time_constant: 0.5,
+            exponent: 1.0,
+        },
+        Rheology::Bingham {
+            plastic_viscosity: 3.0,
+            yield_stress: 60.0,
+        },
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rheology::Newtonian => "Newtonian",
+            Rheology::PowerLaw { index, .. } if *index < 1.0 => "Shear Thinning",
+            Rheology::PowerLaw { .. } => "Shear Thickening",
+            Rheology::Cross { .. } => "Cross",
+            Rheology::Bingham { .. } => "Bingham",
+        }
+    }
+
+    /// Effective viscosity at `shear_rate`, `newtonian` is the viscosity without a model
+    pub fn viscosity(&self, newtonian: f32, shear_rate: f32) -> f32 {
+        let shear_rate = shear_rate.max(MIN_SHEAR_RATE);
+        let viscosity = match *self {
+            Rheology::Newtonian => newtonian,
+            Rheology::PowerLaw { consistency, index } => consistency * shear_rate.powf(index - 1.0),
+            Rheology::Cross {
+                zero_shear,
+                infinite_shear,
+                time_constant,
+                exponent,
+            } => {
+                infinite_shear
+                    + (zero_shear - infinite_shear)
+                        / (1.0 + (time_constant * shear_rate).powf(exponent))
+            }
+            Rheology::Bingham {
+                plastic_viscosity,
+                yield_stress,
+            } => plastic_viscosity + yield_stress / shear_rate,
+        };
+        if viscosity.is_finite() {
+            viscosity.clamp(0.0, MAX_VISCOSITY)
+        } else {
+            MAX_VISCOSITY
+        }
+    }
+}
+
+/// Viscoelastic springs between neighboring particles of the same material, after Clavet et al.
+/// 2005. Springs appear between particles that come within the smoothing radius, let their rest
+/// length follow large deformations and break once they or their rest length grow past the
+/// radius.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Elasticity {
+    /// Spring stiffness per unit (reduced) mass
+    pub stiffness: f32,
+    /// Fraction of its rest length a spring stretches or compresses before it yields
+    pub yield_ratio: f32,
+    /// Rate the rest length follows the deformation beyond the yield ratio, per second. Zero is a
+    /// purely elastic jelly, large values a goo that keeps its new shape.
+    pub plasticity: f32,
+}
+
+impl Default for Elasticity {
+    fn default() -> Self {
+        Self {
+            stiffness: 1200.0,
+            yield_ratio: 0.1,
+            plasticity: 0.5,
+        }
+    }
+}
+
+/// Sand-like grains: Coulomb friction between neighboring grains and no tension, so piles keep
+/// a slope up to the angle of repose. Liquid between the grains lubricates them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Granular {
+    /// Angle of repose of the dry material in degrees
+    pub friction_angle: f32,
+    /// Fraction of the friction left for grains fully surrounded by liquid
+    pub wet_friction: f32,
+}
+
+impl Default for Granular {
+    fn default() -> Self {
+        Self {
+            friction_angle: 34.0,
+            wet_friction: 0.4,
+        }
+    }
+}
+
+impl Granular {
+    /// Friction coefficient `μ = tan φ`, reduced towards `wet_friction` with the wetness
+    pub fn friction(&self, wetness: f32) -> f32 {
+        let wet_factor = 1.0 + (self.wet_friction - 1.0) * wetness.clamp(0.0, 1.0);
+        self.friction_angle.to_radians().tan() * wet_factor
+    }
+}
+
+/// Properties of one fluid phase. The optional fields override the global `SimParameters`.
+#[derive(Clone, Debug)]
+pub struct FluidMaterial {
+    /// Mass per unit area at rest. Particles get their mass `rest_density * spacing²` from it, so
+    /// lighter phases float on heavier ones. The solvers do not compare against this value but
+    /// against the SPH density of the spawn lattice for that mass, which only approaches it for
+    /// small smoothing radii; see [`EquationOfState::rest_density`] to pin the SPH density.
+    pub rest_density: f32,
+    pub color: Color,
+    pub viscosity: Option<f32>,
+    pub rheology: Rheology,
+    pub surface_tension: Option<f32>,
+    /// Pressure law, including the stiffness and an explicit rest density if needed
+    pub equation_of_state: Option<EquationOfState>,
+    /// Makes the material a viscoelastic goo held together by springs between its particles
+    pub elasticity: Option<Elasticity>,
+    /// Makes the material behave like sand instead of a liquid
+    pub granular: Option<Granular>,
+}
+
+impl Default for FluidMaterial {
+    fn default() -> Self {
+        Self {
+            rest_density: 1.0,
+            color: Color::WHITE,
+            viscosity: None,
+            rheology: Rheology::Newtonian,
+            surface_tension: None,
+            equation_of_state: None,
+            elasticity: None,
+            granular: None,
+        }
+    }
+}
+
+#[derive(Resource, Default, Clone, Debug)]
+pub struct FluidMaterials {
+    pub materials: HashMap<usize, FluidMaterial>,
+    /// Adhesion strength keyed by (fluid material, solid material)
+    pub adhesion: HashMap<(usize, usize), f32>,
+}
+
+impl FluidMaterials {
+    pub fn get(&self, id: MaterialId) -> Option<&FluidMaterial> {
+        self.materials.get(&id.0)
+    }
+
+    pub fn rest_density(&self, id: MaterialId) -> f32 {
+        self.get(id).map_or(1.0, |material| material.rest_density)
+    }
+
+    pub fn viscosity(&self, id: MaterialId, default: f32) -> f32 {
+        self.get(id)
+            .and_then(|material| material.viscosity)
+            .unwrap_or(default)
+    }
+
+    /// Viscosity of the material at the given shear rate
+    pub fn effective_viscosity(&self, id: MaterialId, default: f32, shear_rate: f32) -> f32 {
+        let newtonian = self.viscosity(id, default);
+        self.get(id).map_or(newtonian, |material| {
+            material.rheology.viscosity(newtonian, shear_rate)
+        })
+    }
+
+    pub fn surface_tension(&self, id: MaterialId, default: f32) -> f32 {
+        self.get(id)
+            .and_then(|material| material.surface_tension)
+            .unwrap_or(default)
+    }
+
+    pub fn equation_of_state(&self, id: MaterialId, default: EquationOfState) -> EquationOfState {
+        self.get(id)
+            .and_then(|material| material.equation_of_state)
+            .unwrap_or(default)
+    }
+
+    pub fn elasticity(&self, id: MaterialId) -> Option<Elasticity> {
+        self.get(id).and_then(|material| material.elasticity)
+    }
+
+    pub fn granular(&self, id: MaterialId) -> Option<Granular> {
+        self.get(id).and_then(|material| material.granular)
+    }
+
+    pub fn adhesion(&self, fluid: MaterialId, solid: usize, default: f32) -> f32 {
+        self.adhesion
+            .get(&(fluid.0, solid))
+            .copied()
+            .unwrap_or(default)
+    }
+}
+
+pub fn load_fluid_materials(mut materials: ResMut<FluidMaterials>) {
+    // The light blue particles are the water everything else sinks in
+    materials.materials.insert(
+        0,
+        FluidMaterial {
+            color: Color::Srgba(Srgba::rgba_u8(100, 100, 255, 20)),
+            ..default()
+        },
+    );
+    // The heavy red particles behave like honey
+    materials.materials.insert(
+        1,
+        FluidMaterial {
+            rest_density: 4.0,
+            color: Color::Srgba(Srgba::rgba_u8(255, 100, 100, 20)),
+            viscosity: Some(18.0),
+            // Honey barely compresses
+            equation_of_state: Some(EquationOfState {
+                model: PressureModel::Tait,
+                ..default()
+            }),
+            ..default()
+        },
+    );
+    // The green particles bead up and stick to walls
+    materials.materials.insert(
+        2,
+        FluidMaterial {
+            rest_density: 7.0,
+            color: Color::Srgba(Srgba::rgba_u8(100, 255, 100, 20)),
+            surface_tension: Some(360.0),
+            ..default()
+        },
+    );
+    materials.adhesion.insert((2, 0), 180.0);
+    // Sand sinks in water and piles up on the floor
+    materials.materials.insert(
+        3,
+        FluidMaterial {
+            rest_density: 3.0,
+            color: Color::Srgba(Srgba::rgba_u8(230, 200, 120, 20)),
+            surface_tension: Some(0.0),
+            granular: Some(Granular::default()),
+            ..default()
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rheology_follows_shear_rate() {
+        let [newtonian, thinning, thickening, cross, bingham] = Rheology::ALL;
+        assert_eq!(
+            newtonian.viscosity(3.0, 0.1),
+            newtonian.viscosity(3.0, 10.0)
+        );
+        for model in [thinning, cross, bingham] {
+            assert!(
+                model.viscosity(3.0, 0.1) > model.viscosity(3.0, 10.0),
+                "{} does not thin with shear",
+                model.name()
+            );
+        }
+        assert!(thickening.viscosity(3.0, 0.1) < thickening.viscosity(3.0, 10.0));
+        // A Bingham fluid at rest is as stiff as the models allow
+        assert_eq!(bingham.viscosity(3.0, 0.0), MAX_VISCOSITY);
+    }
+
+    #[test]
+    fn water_lubricates_grains() {
+        let sand = Granular::default();
+        assert!((sand.friction(0.0) - 34f32.to_radians().tan()).abs() < 1e-6);
+        assert!(sand.friction(1.0) < sand.friction(0.5));
+        assert!(sand.friction(0.5) < sand.friction(0.0));
+        assert_eq!(sand.friction(2.0), sand.friction(1.0));
+    }
+}
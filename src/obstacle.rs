@@ -0,0 +1,377 @@
+use bevy::prelude::*;
+use std::f32::consts::PI;
+
+/// Particles closer than this to an obstacle surface get pushed out
+pub const COLLISION_MARGIN: f32 = 0.1;
+
+/// Signed distances sampled on a regular grid in obstacle-local space, centered on the origin.
+#[derive(Clone, Debug)]
+pub struct SdfGrid {
+    pub width: usize,
+    pub height: usize,
+    pub cell_size: f32,
+    /// Row-major, row 0 is the bottom of the grid
+    pub distances: Vec<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ObstacleShape {
+    Circle {
+        radius: f32,
+    },
+    /// Segment along the local x axis, rounded by `radius`
+    Capsule {
+        half_length: f32,
+        radius: f32,
+    },
+    Box {
+        half_extents: Vec2,
+    },
+    ConvexPolygon {
+        vertices: Vec<Vec2>,
+    },
+    Sdf(SdfGrid),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlipCondition {
+    /// Particles keep their tangential velocity along the surface
+    #[default]
+    FreeSlip,
+    /// Particles touching the surface take on the velocity of the obstacle
+    NoSlip,
+}
+
+/// A solid object inside the fluid, placed and moved through its `Transform`.
+/// Only the translation, the rotation around z and the x scale are taken into account.
+#[derive(Component, Clone, Debug)]
+#[require(Transform, ObstacleMotion)]
+pub struct Obstacle {
+    pub shape: ObstacleShape,
+    pub slip: SlipCondition,
+}
+
+/// Velocity of an obstacle in units per second, derived from how its `Transform` moved during the
+/// last fixed tick
+#[derive(Component, Clone, Debug, Default)]
+pub struct ObstacleMotion {
+    pub previous: Option<Vec2>,
+    pub velocity: Vec2,
+}
+
+/// Turns into an [`Obstacle`] with an [`ObstacleShape::Sdf`] as soon as the image is loaded.
+/// Pixels brighter than `threshold` are solid; each pixel covers `cell_size` world units.
+#[derive(Component, Clone, Debug)]
+#[require(Transform)]
+pub struct ImageObstacle {
+    pub image: Handle<Image>,
+    pub cell_size: f32,
+    pub threshold: f32,
+    pub slip: SlipCondition,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ObstacleContact {
+    /// Negative inside the obstacle
+    pub distance: f32,
+    /// Unit surface normal pointing out of the obstacle
+    pub normal: Vec2,
+}
+
+impl SdfGrid {
+    pub fn from_grayscale(image: &Image, cell_size: f32, threshold: f32) -> Option<Self> {
+        let size = image.size();
+        let (width, height) = (size.x as usize, size.y as usize);
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        let mut solid = vec![false; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                // Image rows go top to bottom, the grid goes bottom to top
+                let color = image.get_color_at(x as u32, (height - 1 - y) as u32).ok()?;
+                solid[x + y * width] = color.luminance() > threshold;
+            }
+        }
+
+        let is_boundary = |x: usize, y: usize| {
+            let here = solid[x + y * width];
+            [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().any(|(dx, dy)| {
+                let nx = x as i32 + dx;
+                let ny = y as i32 + dy;
+                if nx < 0 || ny < 0 || nx >= width as i32 || ny >= height as i32 {
+                    return here;
+                }
+                solid[nx as usize + ny as usize * width] != here
+            })
+        };
+        let boundary = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .filter(|&(x, y)| is_boundary(x, y))
+            .map(|(x, y)| Vec2::new(x as f32, y as f32))
+            .collect::<Vec<_>>();
+
+        // Brute force is fine here, this only runs once when the obstacle is created
+        let mut distances = vec![f32::MAX; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let pos = Vec2::new(x as f32, y as f32);
+                let nearest = boundary
+                    .iter()
+                    .map(|b| b.distance(pos))
+                    .fold(f32::MAX, f32::min);
+                // Boundary pixels sit half a pixel from the actual surface
+                let dist = (nearest + 0.5) * cell_size;
+                distances[x + y * width] = if solid[x + y * width] { -dist } else { dist };
+            }
+        }
+
+        Some(Self {
+            width,
+            height,
+            cell_size,
+            distances,
+        })
+    }
+
+    fn at(&self, x: usize, y: usize) -> f32 {
+        self.distances[x.min(self.width - 1) + y.min(self.height - 1) * self.width]
+    }
+
+    /// Bilinear sample; points outside the grid add their distance to the grid border
+    pub fn sample(&self, local: Vec2) -> f32 {
+        let half = Vec2::new(self.width as f32, self.height as f32) * self.cell_size * 0.5;
+        let grid_pos = (local + half) / self.cell_size - Vec2::splat(0.5);
+        let max = Vec2::new(self.width as f32 - 1.0, self.height as f32 - 1.0);
+        let clamped = grid_pos.clamp(Vec2::ZERO, max);
+        let outside = (grid_pos - clamped).length() * self.cell_size;
+
+        let x0 = clamped.x.floor() as usize;
+        let y0 = clamped.y.floor() as usize;
+        let t = clamped - Vec2::new(x0 as f32, y0 as f32);
+        let bottom = self.at(x0, y0) * (1.0 - t.x) + self.at(x0 + 1, y0) * t.x;
+        let top = self.at(x0, y0 + 1) * (1.0 - t.x) + self.at(x0 + 1, y0 + 1) * t.x;
+        bottom * (1.0 - t.y) + top * t.y + outside
+    }
+}
+
+impl ObstacleShape {
+    pub fn signed_distance(&self, p: Vec2) -> f32 {
+        match self {
+            ObstacleShape::Circle { radius } => p.length() - radius,
+            ObstacleShape::Capsule {
+                half_length,
+                radius,
+            } => {
+                let on_axis = Vec2::new(p.x.clamp(-half_length, *half_length), 0.0);
+                p.distance(on_axis) - radius
+            }
+            ObstacleShape::Box { half_extents } => {
+                let q = p.abs() - *half_extents;
+                q.max(Vec2::ZERO).length() + q.x.max(q.y).min(0.0)
+            }
+            ObstacleShape::ConvexPolygon { vertices } => polygon_signed_distance(vertices, p),
+            ObstacleShape::Sdf(grid) => grid.sample(p),
+        }
+    }
+
+    /// Radius of a circle around the local origin containing the whole shape
+    pub fn bounding_radius(&self) -> f32 {
+        match self {
+            ObstacleShape::Circle { radius } => *radius,
+            ObstacleShape::Capsule {
+                half_length,
+                radius,
+            } => half_length + radius,
+            ObstacleShape::Box { half_extents } => half_extents.length(),
+            ObstacleShape::ConvexPolygon { vertices } => {
+                vertices.iter().map(|v| v.length()).fold(0.0, f32::max)
+            }
+            ObstacleShape::Sdf(grid) => {
+                Vec2::new(grid.width as f32, grid.height as f32).length() * grid.cell_size * 0.5
+            }
+        }
+    }
+
+    /// Mass and moment of inertia around the local origin for a uniform `density`
+    pub fn mass_properties(&self, density: f32) -> (f32, f32) {
+        match self {
+            ObstacleShape::Circle { radius } => {
+                let mass = density * PI * radius * radius;
+                (mass, 0.5 * mass * radius * radius)
+            }
+            ObstacleShape::Capsule {
+                half_length,
+                radius,
+            } => {
+                // Rectangle in the middle plus the two half circles as one circle at the center
+                let rect_mass = density * 4.0 * half_length * radius;
+                let rect_inertia =
+                    rect_mass * (4.0 * half_length * half_length + 4.0 * radius * radius) / 12.0;
+                let cap_mass = density * PI * radius * radius;
+                let cap_inertia =
+                    0.5 * cap_mass * radius * radius + cap_mass * half_length * half_length;
+                (rect_mass + cap_mass, rect_inertia + cap_inertia)
+            }
+            ObstacleShape::Box { half_extents } => {
+                let size = *half_extents * 2.0;
+                let mass = density * size.x * size.y;
+                (mass, mass * size.length_squared() / 12.0)
+            }
+            ObstacleShape::ConvexPolygon { vertices } => {
+                let mut area = 0.0;
+                let mut inertia = 0.0;
+                for (i, &a) in vertices.iter().enumerate() {
+                    let b = vertices[(i + 1) % vertices.len()];
+                    let cross = a.perp_dot(b);
+                    area += cross * 0.5;
+                    inertia += cross * (a.dot(a) + a.dot(b) + b.dot(b)) / 12.0;
+                }
+                (density * area.abs(), density * inertia.abs())
+            }
+            ObstacleShape::Sdf(grid) => {
+                let cell_area = grid.cell_size * grid.cell_size;
+                let half = Vec2::new(grid.width as f32, grid.height as f32) * 0.5;
+                let mut mass = 0.0;
+                let mut inertia = 0.0;
+                for y in 0..grid.height {
+                    for x in 0..grid.width {
+                        if grid.at(x, y) < 0.0 {
+                            let offset =
+                                (Vec2::new(x as f32 + 0.5, y as f32 + 0.5) - half) * grid.cell_size;
+                            mass += density * cell_area;
+                            inertia += density * cell_area * offset.length_squared();
+                        }
+                    }
+                }
+                (mass, inertia)
+            }
+        }
+    }
+
+    /// Cheap bounding circle test to skip the distance evaluation for far away points
+    pub fn is_near(&self, transform: &Transform, world_pos: Vec2, margin: f32) -> bool {
+        let reach = self.bounding_radius() * transform.scale.x.abs() + margin;
+        world_pos.distance_squared(transform.translation.truncate()) <= reach * reach
+    }
+
+    /// Signed distance and outward normal at a point given in world space
+    pub fn probe(&self, transform: &Transform, world_pos: Vec2) -> ObstacleContact {
+        let scale = transform.scale.x.abs().max(f32::EPSILON);
+        let angle = transform.rotation.to_euler(EulerRot::ZYX).0;
+        let rotation = Vec2::from_angle(angle);
+        let local =
+            Vec2::from_angle(-angle).rotate(world_pos - transform.translation.truncate()) / scale;
+
+        const EPS: f32 = 1e-3;
+        let gradient = Vec2::new(
+            self.signed_distance(local + Vec2::X * EPS)
+                - self.signed_distance(local - Vec2::X * EPS),
+            self.signed_distance(local + Vec2::Y * EPS)
+                - self.signed_distance(local - Vec2::Y * EPS),
+        );
+        ObstacleContact {
+            distance: self.signed_distance(local) * scale,
+            normal: rotation.rotate(gradient.normalize_or(Vec2::Y)),
+        }
+    }
+}
+
+/// Signed distance to a simple polygon, negative inside
+fn polygon_signed_distance(vertices: &[Vec2], p: Vec2) -> f32 {
+    let Some(&first) = vertices.first() else {
+        return f32::MAX;
+    };
+    let mut dist_sq = (p - first).length_squared();
+    let mut sign = 1.0;
+    let mut j = vertices.len() - 1;
+    for (i, &a) in vertices.iter().enumerate() {
+        let b = vertices[j];
+        let edge = b - a;
+        let to_p = p - a;
+        let t = (to_p.dot(edge) / edge.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+        dist_sq = dist_sq.min((to_p - edge * t).length_squared());
+
+        let crosses_up = p.y >= a.y;
+        let crosses_down = p.y < b.y;
+        let left = edge.x * to_p.y > edge.y * to_p.x;
+        if (crosses_up && crosses_down && left) || (!crosses_up && !crosses_down && !left) {
+            sign = -sign;
+        }
+        j = i;
+    }
+    sign * dist_sq.sqrt()
+}
+
+pub fn track_obstacle_motion(
+    mut obstacles: Query<(&Transform, &mut ObstacleMotion)>,
+    time: Res<Time>,
+) {
+    let dt = time.delta_secs();
+    for (transform, mut motion) in obstacles.iter_mut() {
+        let current = transform.translation.truncate();
+        motion.velocity = motion
+            .previous
+            .filter(|_| dt > 0.0)
+            .map(|prev| (current - prev) / dt)
+            .unwrap_or_default();
+        motion.previous = Some(current);
+    }
+}
+
+pub fn build_image_obstacles(
+    mut commands: Commands,
+    sources: Query<(Entity, &ImageObstacle)>,
+    images: Res<Assets<Image>>,
+) {
+    for (entity, source) in sources.iter() {
+        let Some(image) = images.get(&source.image) else {
+            continue;
+        };
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.remove::<ImageObstacle>();
+        match SdfGrid::from_grayscale(image, source.cell_size, source.threshold) {
+            Some(grid) => {
+                entity_commands.insert(Obstacle {
+                    shape: ObstacleShape::Sdf(grid),
+                    slip: source.slip,
+                });
+            }
+            None => warn!(
+                "Could not build an SDF from obstacle image {:?}",
+                source.image
+            ),
+        }
+    }
+}
+
+/// Keeps a particle out of every obstacle, returning the corrected position and velocity
+pub fn collide_obstacles<'a>(
+    mut pos: Vec2,
+    mut vel: Vec2,
+    obstacles: impl Iterator<Item = (&'a Obstacle, &'a Transform, &'a ObstacleMotion)>,
+) -> (Vec2, Vec2) {
+    for (obstacle, transform, motion) in obstacles {
+        if !obstacle.shape.is_near(transform, pos, COLLISION_MARGIN) {
+            continue;
+        }
+        let contact = obstacle.shape.probe(transform, pos);
+        if contact.distance >= COLLISION_MARGIN {
+            continue;
+        }
+        pos += contact.normal * (COLLISION_MARGIN - contact.distance);
+
+        let relative = vel - motion.velocity;
+        let normal_speed = relative.dot(contact.normal);
+        let relative = match obstacle.slip {
+            SlipCondition::NoSlip => Vec2::ZERO,
+            SlipCondition::FreeSlip if normal_speed < 0.0 => {
+                relative - contact.normal * normal_speed
+            }
+            SlipCondition::FreeSlip => relative,
+        };
+        vel = motion.velocity + relative;
+    }
+    (pos, vel)
+}
@@ -2,9 +2,47 @@ use crate::basic_assets::{
     MaterialColorDatabase, MeshShapeDatabase, ParticleAssetPlugin, SimAssetId,
 };
 use crate::camera::MousePosition;
-use crate::chunk::{Chunk, ChunkPosition, EntityLookupChunk, CHUNK_SIZE};
+use crate::chunk::{ChunkPosition, EntityLookupChunk, CHUNK_SIZE};
 use crate::debug::ParticleDebugPlugin;
-use bevy::ecs::entity;
+use crate::dfsph::{
+    dfsph_apply_forces, dfsph_compute_factors, dfsph_correct_velocity, dfsph_density_error,
+    dfsph_divergence_error, dfsph_store_pressure, run_dfsph_density_solve,
+    run_dfsph_divergence_solve, DfsphDensityIteration, DfsphDivergenceIteration, DfsphParameters,
+    DfsphState,
+};
+use crate::domain::SimDomain;
+use crate::elasticity::{calc_spring_force, update_springs, Springs};
+use crate::emitter::{absorb_particles, emit_particles};
+use crate::granular::{calc_granular_friction, calc_wetness, Wetness};
+use crate::integrator::{measure_energy, Integrator, SimEnergy};
+use crate::kernel::{lattice_density, Kernel, KernelKind, SmoothingKernel, Spiky};
+use crate::material::{load_fluid_materials, EquationOfState, FluidMaterials, MaterialId};
+use crate::obstacle::{
+    build_image_obstacles, collide_obstacles, track_obstacle_motion, Obstacle, ObstacleMotion,
+    ObstacleShape,
+};
+use crate::pbf::{
+    pbf_predict, pbf_solve_density, pbf_store_pressure, pbf_update_velocity,
+    pbf_vorticity_confinement, DensityLambda, PbfParameters,
+};
+use crate::pcisph::{
+    pcisph_correct_pressure, pcisph_init, pcisph_integrate, pcisph_predict, pcisph_pressure_force,
+    pcisph_store_pressure, run_pcisph_iterations, PcisphIteration, PcisphParameters,
+    PcisphPressure,
+};
+use crate::rigid_body::{
+    collide_rigid_bodies, couple_rigid_bodies, integrate_rigid_bodies, FluidRigidBody,
+};
+use crate::solver::{solver_is, SolverKind, SolverStats};
+use crate::surface_tension::{
+    calc_adhesion, calc_surface_normals, calc_surface_tension, SurfaceNormal,
+};
+use crate::thermal::{apply_heat_sources, calc_heat_diffusion, Temperature, ThermalParameters};
+use crate::viscosity::{
+    apply_xsph, calc_effective_viscosity, calc_viscosity_force, EffectiveViscosity,
+};
+use crate::vorticity::{calc_vorticity, calc_vorticity_confinement, Vorticity};
+use bevy::ecs::schedule::ScheduleLabel;
 use bevy::prelude::*;
 use rand::Rng;
 
@@ -17,16 +55,127 @@ pub struct Velocity(pub Vec2);
 #[derive(Component, Default, Clone, Debug)]
 pub struct Acceleration(pub Vec2);
 
+/// Acceleration of the previous step, needed by integrators that average it with the new one
+#[derive(Component, Default, Clone, Debug)]
+pub struct PreviousAcceleration(pub Vec2);
+
 #[derive(Component, Default, Clone, Debug)]
 pub struct Mass(pub f32);
 
 #[derive(Component, Default, Clone, Debug)]
 pub struct LocalMassDensity(pub f32);
 
-#[derive(Default, Clone, Debug, Resource)]
+/// Mass density with the sharper near kernel, see [`Kernel::near`]
+#[derive(Component, Default, Clone, Debug)]
+pub struct NearDensity(pub f32);
+
+/// Pressure of the particle. The explicit SPH solver evaluates the material's `EquationOfState`,
+/// the incompressible solvers store the pressure of their last solve, so the coupling with
+/// obstacles and rigid bodies and the granular friction push as hard under every solver.
+#[derive(Component, Default, Clone, Debug)]
+pub struct Pressure(pub f32);
+
+/// One simulation substep. `FixedUpdate` runs it until the whole tick is covered, see
+/// [`run_sim_steps`].
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SimStep;
+
+/// All rates are per second and all accelerations per second squared, so results only depend on
+/// `dt` and `substeps`, never on the frame rate.
+#[derive(Clone, Debug, Resource)]
 pub struct SimParameters {
-    pub pressure_mult: f32,
+    /// Length of one `FixedUpdate` tick in seconds
+    pub dt: f32,
+    /// Number of simulation steps every tick is split into, unless `adaptive_steps` is set
+    pub substeps: u32,
+    /// Pick the substep size from the CFL condition instead of using fixed `substeps`
+    pub adaptive_steps: bool,
+    /// Courant number, the fraction of the smoothing radius a particle may travel per substep
+    pub cfl: f32,
+    /// Bounds for adaptive substeps, in seconds. `max_step` is also capped by `dt`.
+    pub min_step: f32,
+    pub max_step: f32,
+    /// Support radius of the smoothing kernel. The spatial grid uses it as its cell size and the
+    /// initial particle spacing follows it, so smaller values give a finer simulation.
+    pub smoothing_radius: f32,
+    /// Default equation of state for materials without their own
+    pub equation_of_state: EquationOfState,
     pub gravity: f32,
+    /// Default viscosity for materials without their own
+    pub viscosity: f32,
+    /// Share of the velocity difference to the neighbors the XSPH smoothing removes per `dt`,
+    /// 0 disables it
+    pub xsph: f32,
+    /// Default surface tension for materials without their own
+    pub surface_tension: f32,
+    /// Stiffness of the near pressure, a short range repulsion that keeps particles from
+    /// clumping in pairs and gives a crisp surface. 0 disables it.
+    pub near_pressure: f32,
+    /// Strength of the vorticity confinement, 0 disables it
+    pub vorticity_confinement: f32,
+    /// Default adhesion for material pairs missing from the adhesion table
+    pub adhesion: f32,
+    /// Length of the substep currently being run
+    step: f32,
+}
+
+impl Default for SimParameters {
+    fn default() -> Self {
+        Self {
+            dt: 1.0 / 60.0,
+            substeps: 1,
+            adaptive_steps: true,
+            cfl: 0.4,
+            min_step: 1.0 / 1920.0,
+            max_step: 1.0 / 60.0,
+            smoothing_radius: 1.0,
+            equation_of_state: EquationOfState::default(),
+            gravity: 0.0,
+            viscosity: 3.0,
+            xsph: 0.0,
+            surface_tension: 0.0,
+            near_pressure: 0.0,
+            vorticity_confinement: 0.0,
+            adhesion: 0.0,
+            step: 1.0 / 60.0,
+        }
+    }
+}
+
+/// Share of the viscous diffusion time `h² ρ / μ` one substep may cover, explicit viscosity
+/// overshoots beyond it
+const VISCOUS_STEP_FACTOR: f32 = 0.125;
+
+impl SimParameters {
+    /// Time advanced by the current substep
+    pub fn step_dt(&self) -> f32 {
+        self.step
+    }
+
+    /// Largest substep allowed by the CFL condition for the given maximum particle speed and
+    /// acceleration and by the viscous limit for the largest kinematic viscosity `μ / ρ`, clamped
+    /// to `min_step..=max_step`
+    pub fn cfl_step(
+        &self,
+        smoothing_radius: f32,
+        max_speed: f32,
+        max_acceleration: f32,
+        max_kinematic_viscosity: f32,
+    ) -> f32 {
+        let mut step = self.max_step.min(self.dt);
+        if max_speed > 0.0 {
+            step = step.min(self.cfl * smoothing_radius / max_speed);
+        }
+        if max_acceleration > 0.0 {
+            step = step.min(self.cfl * (smoothing_radius / max_acceleration).sqrt());
+        }
+        if max_kinematic_viscosity > 0.0 {
+            step = step.min(
+                VISCOUS_STEP_FACTOR * smoothing_radius * smoothing_radius / max_kinematic_viscosity,
+            );
+        }
+        step.max(self.min_step)
+    }
 }
 
 #[derive(Bundle, Clone, Default, Debug)]
@@ -34,9 +183,36 @@ pub struct ParticleBundle {
     pub particle: Particle,
     pub physics: ParticlePhysicsBundle,
     pub chunk_position: ChunkPosition,
+    pub material: MaterialId,
     pub visual: ParticleVisualBundle,
 }
 
+impl ParticleBundle {
+    pub fn new(
+        position: Vec2,
+        velocity: Vec2,
+        mass: f32,
+        material: MaterialId,
+        chunk_position: ChunkPosition,
+        visual: ParticleVisualBundle,
+    ) -> Self {
+        Self {
+            particle: Particle,
+            physics: ParticlePhysicsBundle {
+                transform: Transform::from_xyz(position.x, position.y, 0.0),
+                predicted_pos: PredictedPos(position),
+                velocity: Velocity(velocity),
+                mass: Mass(mass),
+                local_mass_density: LocalMassDensity(0.5),
+                ..Default::default()
+            },
+            chunk_position,
+            material,
+            visual,
+        }
+    }
+}
+
 #[derive(Component, Default, Clone, Debug)]
 pub struct Particle;
 
@@ -53,53 +229,227 @@ pub struct ParticlePhysicsBundle {
     pub velocity: Velocity,
     pub mass: Mass,
     pub local_mass_density: LocalMassDensity,
+    pub near_density: NearDensity,
+    pub pressure: Pressure,
+    pub vorticity: Vorticity,
+    pub temperature: Temperature,
+    pub effective_viscosity: EffectiveViscosity,
     pub acceleration: Acceleration,
+    pub previous_acceleration: PreviousAcceleration,
+    pub surface_normal: SurfaceNormal,
+    pub springs: Springs,
+    pub wetness: Wetness,
 }
 
-pub struct ParticlePlugin;
+#[derive(Default)]
+pub struct ParticlePlugin {
+    pub kernel: KernelKind,
+    pub integrator: Integrator,
+    pub solver: SolverKind,
+}
 
 impl Plugin for ParticlePlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<EntityLookupChunk>()
+        let radius = SimParameters::default().smoothing_radius;
+        app.insert_resource(Kernel::new(self.kernel, radius))
+            .insert_resource(EntityLookupChunk::new(radius))
+            .insert_resource(self.integrator)
+            .insert_resource(self.solver)
+            .init_resource::<PbfParameters>()
+            .init_resource::<PcisphParameters>()
+            .init_resource::<DfsphParameters>()
+            .init_resource::<SolverStats>()
+            .init_resource::<ThermalParameters>()
+            .register_required_components::<Particle, DensityLambda>()
+            .register_required_components::<Particle, PcisphPressure>()
+            .register_required_components::<Particle, DfsphState>()
+            .init_resource::<SimEnergy>()
             .init_resource::<SimParameters>()
+            .init_resource::<SimDomain>()
+            .init_resource::<FluidMaterials>()
             .add_plugins(ParticleAssetPlugin)
-            .add_systems(Startup, spawn_particles)
+            .add_systems(PreStartup, load_fluid_materials)
+            .add_systems(Startup, (apply_smoothing_radius, spawn_particles).chain())
+            .add_systems(Update, build_image_obstacles)
+            .add_systems(
+                PreUpdate,
+                sync_fixed_timestep.run_if(resource_changed::<SimParameters>),
+            )
             .add_systems(
-                Update,
+                FixedUpdate,
                 (
-                    calc_pred_pos,
+                    track_obstacle_motion,
+                    emit_particles,
+                    absorb_particles,
+                    run_sim_steps,
+                    measure_energy,
+                )
+                    .chain(),
+            )
+            .add_systems(
+                SimStep,
+                (
+                    apply_smoothing_radius,
+                    (
+                        calc_pred_pos,
+                        update_chunk_positions,
+                        calc_local_mass_density,
+                        calc_pressure,
+                        calc_surface_normals,
+                        calc_pressure_force,
+                        (calc_vorticity, calc_vorticity_confinement).chain(),
+                        calc_near_pressure_force,
+                        (calc_effective_viscosity, calc_viscosity_force).chain(),
+                        (update_springs, calc_spring_force).chain(),
+                        (calc_wetness, calc_granular_friction).chain(),
+                        calc_surface_tension,
+                        couple_rigid_bodies,
+                        calc_adhesion,
+                        // artificial_motion,
+                        // mouse_interact,
+                        // smooth_flow,
+                        calc_velocity,
+                        apply_xsph,
+                        integrate_rigid_bodies,
+                        update_particle_pos,
+                    )
+                        .chain()
+                        .run_if(solver_is(SolverKind::Sph)),
+                    (
+                        reset_predicted_positions,
+                        update_chunk_positions,
+                        calc_local_mass_density,
+                        calc_surface_normals,
+                        calc_gravity_force,
+                        calc_near_pressure_force,
+                        (calc_effective_viscosity, calc_viscosity_force).chain(),
+                        (update_springs, calc_spring_force).chain(),
+                        (calc_wetness, calc_granular_friction).chain(),
+                        calc_surface_tension,
+                        couple_rigid_bodies,
+                        calc_adhesion,
+                        pbf_predict,
+                        update_chunk_positions,
+                        (pbf_solve_density, pbf_store_pressure).chain(),
+                        pbf_update_velocity,
+                        (calc_vorticity, pbf_vorticity_confinement).chain(),
+                        apply_xsph,
+                        integrate_rigid_bodies,
+                        commit_predicted_positions,
+                    )
+                        .chain()
+                        .run_if(solver_is(SolverKind::PositionBased)),
+                    (
+                        reset_predicted_positions,
+                        update_chunk_positions,
+                        calc_local_mass_density,
+                        calc_surface_normals,
+                        calc_gravity_force,
+                        calc_near_pressure_force,
+                        (calc_vorticity, calc_vorticity_confinement).chain(),
+                        (calc_effective_viscosity, calc_viscosity_force).chain(),
+                        (update_springs, calc_spring_force).chain(),
+                        (calc_wetness, calc_granular_friction).chain(),
+                        calc_surface_tension,
+                        couple_rigid_bodies,
+                        calc_adhesion,
+                        pcisph_init,
+                        (run_pcisph_iterations, pcisph_store_pressure).chain(),
+                        pcisph_integrate,
+                        apply_xsph,
+                        drift_predicted_positions,
+                        integrate_rigid_bodies,
+                        commit_predicted_positions,
+                    )
+                        .chain()
+                        .run_if(solver_is(SolverKind::Pcisph)),
+                    (
+                        reset_predicted_positions,
+                        update_chunk_positions,
+                        calc_local_mass_density,
+                        (dfsph_compute_factors, run_dfsph_divergence_solve).chain(),
+                        calc_surface_normals,
+                        calc_gravity_force,
+                        calc_near_pressure_force,
+                        (calc_vorticity, calc_vorticity_confinement).chain(),
+                        (calc_effective_viscosity, calc_viscosity_force).chain(),
+                        (update_springs, calc_spring_force).chain(),
+                        (calc_wetness, calc_granular_friction).chain(),
+                        calc_surface_tension,
+                        couple_rigid_bodies,
+                        calc_adhesion,
+                        dfsph_apply_forces,
+                        (run_dfsph_density_solve, dfsph_store_pressure).chain(),
+                        apply_xsph,
+                        drift_predicted_positions,
+                        integrate_rigid_bodies,
+                        commit_predicted_positions,
+                    )
+                        .chain()
+                        .run_if(solver_is(SolverKind::Dfsph)),
+                    (apply_heat_sources, calc_heat_diffusion).chain(),
+                )
+                    .chain(),
+            )
+            .add_systems(
+                PcisphIteration,
+                (
+                    pcisph_predict,
+                    update_chunk_positions,
                     calc_local_mass_density,
-                    calc_pressure_force,
-                    // artificial_motion,
-                    // mouse_interact,
-                    // smooth_flow,
-                    calc_velocity,
-                    update_particle_pos,
+                    pcisph_correct_pressure,
+                    pcisph_pressure_force,
                 )
                     .chain(),
             )
+            .add_systems(
+                DfsphDivergenceIteration,
+                (dfsph_divergence_error, dfsph_correct_velocity).chain(),
+            )
+            .add_systems(
+                DfsphDensityIteration,
+                (dfsph_density_error, dfsph_correct_velocity).chain(),
+            )
             .add_plugins(ParticleDebugPlugin);
     }
 }
 
 pub fn spawn_particles(
     mut commands: Commands,
-    mut chunk: ResMut<EntityLookupChunk>,
+    chunk: Res<EntityLookupChunk>,
     colors: Res<MaterialColorDatabase>,
     meshes: Res<MeshShapeDatabase>,
+    materials: Res<FluidMaterials>,
+    domain: Res<SimDomain>,
 ) {
-    for x in 0..CHUNK_SIZE {
-        'outer: for y in 0..CHUNK_SIZE {
+    // One particle per cell; the mass is the material's rest density times the area it stands
+    // for, so the rest density does not depend on the resolution
+    let spacing = chunk.cell_size();
+    let outline = domain.shape.outline();
+    let min = outline
+        .iter()
+        .copied()
+        .reduce(Vec2::min)
+        .unwrap_or_default();
+    let max = outline
+        .iter()
+        .copied()
+        .reduce(Vec2::max)
+        .unwrap_or_default();
+    let low = (min / spacing).floor().as_ivec2();
+    let high = (max / spacing).ceil().as_ivec2();
+
+    for x in low.x..=high.x {
+        'outer: for y in low.y..=high.y {
             let mesh_handle = meshes.handles.get(&SimAssetId::Particle).unwrap();
 
             for z in 0..1 {
-                let spawn_code = ((x % 3 + 3 * y + z) % 5);
-                if spawn_code >= 3 {
+                let spawn_code = (x.rem_euclid(3) + 3 * y + z).rem_euclid(5) as usize;
+                if spawn_code >= 4 {
                     continue 'outer;
                 }
-                let mass = (spawn_code * 3 + 1) as f32 * 1.0;
-
-                let material = (spawn_code);
+                let material = spawn_code;
+                let mass = materials.rest_density(MaterialId(material)) * spacing * spacing;
 
                 // let spawn_code = (x + y + z) % 2;
                 // if spawn_code == 2 {
@@ -110,53 +460,123 @@ pub fn spawn_particles(
 
                 let color_handle = colors.handles.get(&material).unwrap();
                 let mut rng = rand::thread_rng();
-                let x_f = x as f32 + (rng.gen::<f32>() - 0.5) * 0.8;
-                let y_f = y as f32 + (rng.gen::<f32>() - 0.5) * 0.8;
-
-                let vel_x: f32 = rng.gen();
-                let vel_y: f32 = rng.gen();
-
-                let particle_bundle = ParticleBundle {
-                    particle: Particle,
-                    physics: ParticlePhysicsBundle {
-                        transform: Transform::from_xyz(x_f, y_f, 0.0),
-                        predicted_pos: PredictedPos(Vec2::new(x_f, y_f)),
-                        velocity: Default::default(),
-                        mass: Mass(mass),
-                        local_mass_density: LocalMassDensity(0.5),
-                        ..Default::default()
-                    },
-                    visual: ParticleVisualBundle {
+                let x_f = (x as f32 + (rng.gen::<f32>() - 0.5) * 0.8) * spacing;
+                let y_f = (y as f32 + (rng.gen::<f32>() - 0.5) * 0.8) * spacing;
+                if !domain.shape.contains(Vec2::new(x_f, y_f)) {
+                    continue;
+                }
+
+                let chunk_position = chunk.chunk_position(x_f, y_f);
+                let particle_bundle = ParticleBundle::new(
+                    Vec2::new(x_f, y_f),
+                    Vec2::ZERO,
+                    mass,
+                    MaterialId(material),
+                    chunk_position,
+                    ParticleVisualBundle {
                         mesh: Mesh2d(mesh_handle.clone()),
                         mesh_material: MeshMaterial2d(color_handle.clone()),
                     },
-                    ..Default::default()
-                };
-                let entity = commands.spawn(particle_bundle).id();
-                chunk
-                    .insert(entity, x, y)
-                    .expect("Adding Entity to Chunk failed");
+                );
+                commands.spawn(particle_bundle);
             }
         }
     }
 }
 
-pub fn calc_velocity(mut particles: Query<(&mut Velocity, &Acceleration), With<Particle>>) {
-    for (mut vel, acc) in particles.iter_mut() {
-        // WARN: TEMPORARY -> = instead of +=
+/// Rebuilds the kernel and re-buckets every particle whenever `SimParameters::smoothing_radius`
+/// changed, keeping the grid cell size equal to the kernel support.
+pub fn apply_smoothing_radius(
+    mut particles: Query<(Entity, &PredictedPos, &mut ChunkPosition), With<Particle>>,
+    mut entity_lookup_chunk: ResMut<EntityLookupChunk>,
+    mut kernel: ResMut<Kernel>,
+    params: Res<SimParameters>,
+) {
+    let radius = params.smoothing_radius.max(MIN_SMOOTHING_RADIUS);
+    if kernel.radius() == radius && entity_lookup_chunk.cell_size() == radius {
+        return;
+    }
+    *kernel = Kernel::new(kernel.kind, radius);
+    entity_lookup_chunk.reset(radius);
+    for (entity, predicted, mut chunk_pos) in particles.iter_mut() {
+        entity_lookup_chunk.relocate(entity, &mut chunk_pos, predicted.0.x, predicted.0.y);
+    }
+}
+
+/// Keeps `Time<Fixed>` ticking at `SimParameters::dt`
+pub fn sync_fixed_timestep(params: Res<SimParameters>, mut time: ResMut<Time<Fixed>>) {
+    let timestep = std::time::Duration::from_secs_f32(params.dt.max(0.0001));
+    if time.timestep() != timestep {
+        time.set_timestep(timestep);
+    }
+}
+
+/// Runs the substeps of a single fixed tick. With `adaptive_steps` the size of every substep is
+/// chosen from the fastest and most accelerated particle, so nothing moves further than a fraction
+/// of the smoothing radius at once, and from the most viscous one, so the viscosity stays stable.
+pub fn run_sim_steps(world: &mut World) {
+    let params = world.resource::<SimParameters>().clone();
+    if !params.adaptive_steps {
+        let substeps = params.substeps.max(1);
+        set_step(world, params.dt / substeps as f32);
+        for _ in 0..substeps {
+            world.run_schedule(SimStep);
+        }
+        return;
+    }
+
+    let mut particles = world.query_filtered::<(
+        &Velocity,
+        &Acceleration,
+        &EffectiveViscosity,
+        &LocalMassDensity,
+    ), With<Particle>>();
+    let mut remaining = params.dt;
+    // Leftovers below a microsecond are rounding noise, not worth another full step
+    while remaining > 1e-6 {
+        let (mut max_speed, mut max_acceleration, mut max_viscosity) = (0.0f32, 0.0f32, 0.0f32);
+        for (vel, acc, effective, density) in particles.iter(world) {
+            max_speed = max_speed.max(vel.0.length());
+            max_acceleration = max_acceleration.max(acc.0.length());
+            if density.0 > 0.0 {
+                max_viscosity = max_viscosity.max(effective.viscosity / density.0);
+            }
+        }
+        let radius = world.resource::<Kernel>().radius();
+        let step = params
+            .cfl_step(radius, max_speed, max_acceleration, max_viscosity)
+            .min(remaining);
+        set_step(world, step);
+        world.run_schedule(SimStep);
+        remaining -= step;
+    }
+}
 
-        let len = vel.0.length();
-        vel.0 = vel.0.clamp_length_max(len * 0.9);
+fn set_step(world: &mut World, step: f32) {
+    // Not a user change, so `sync_fixed_timestep` should not react to it
+    world
+        .resource_mut::<SimParameters>()
+        .bypass_change_detection()
+        .step = step;
+}
 
+pub fn calc_velocity(
+    mut particles: Query<(&mut Velocity, &Acceleration, &PreviousAcceleration), With<Particle>>,
+    params: Res<SimParameters>,
+    integrator: Res<Integrator>,
+) {
+    let dt = params.step_dt();
+    for (mut vel, acc, previous_acc) in particles.iter_mut() {
         let mut rng = rand::thread_rng();
+        let jitter = Vec2::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5) * 1.8;
 
-        vel.0.x += acc.0.x + (rng.gen::<f32>() - 0.5) * 0.0005;
-        vel.0.y += acc.0.y + (rng.gen::<f32>() - 0.5) * 0.0005;
+        vel.0 = integrator.kick(vel.0, previous_acc.0, acc.0 + jitter, dt);
 
         // println!("Vel calc: {}|{}", vel.0.x, vel.0.y);
     }
 }
 
+#[allow(dead_code)]
 pub fn artificial_motion(
     mut particles: Query<(&mut Acceleration, &Transform, &LocalMassDensity), With<Particle>>,
 ) {
@@ -178,19 +598,14 @@ pub fn artificial_motion(
     }
 }
 
+#[allow(dead_code)]
 pub fn mouse_interact(
     mut particles: Query<(&mut Acceleration, &Transform, &LocalMassDensity), With<Particle>>,
     mouse_pos: Res<MousePosition>,
     entity_lookup_chunk: Res<EntityLookupChunk>,
 ) {
-    let chunk_pos = Chunk::<Vec<Entity>>::get_chunk_pos(mouse_pos.0.x, mouse_pos.0.y);
-    if chunk_pos.is_none() {
-        return;
-    }
-    let chunk_pos = chunk_pos.unwrap();
-
-    let entities =
-        entity_lookup_chunk.get_neighborhood_entities(chunk_pos.x as usize, chunk_pos.y as usize);
+    let chunk_pos = entity_lookup_chunk.chunk_position(mouse_pos.0.x, mouse_pos.0.y);
+    let entities = entity_lookup_chunk.get_neighborhood_entities(&chunk_pos);
 
     for entity in entities.iter() {
         let (mut acc, transf, density) = particles.get_mut(*entity).expect("AAAAAHHHHH");
@@ -214,18 +629,16 @@ pub fn mouse_interact(
     }
 }
 
+#[allow(dead_code)]
 pub const RATIO: f32 = 0.1;
 
+#[allow(dead_code)]
 pub fn smooth_flow(
     mut particles: Query<(&mut Acceleration, &LocalMassDensity), With<Particle>>,
     entity_lookup_chunk: Res<EntityLookupChunk>,
 ) {
-    for x in 0..CHUNK_SIZE {
-        for y in 0..CHUNK_SIZE {
-            let entities = entity_lookup_chunk
-                .0
-                .get(x, y)
-                .expect("Should be in bounds");
+    for (_, loaded) in entity_lookup_chunk.loaded_chunks() {
+        for entities in loaded.chunk.cells.iter() {
             let mut accumulated = Vec2::splat(0.0);
             let mut acc_mass = 0.0;
             for entity in entities.iter() {
@@ -249,83 +662,311 @@ pub fn smooth_flow(
     }
 }
 
+/// Evaluates the equation of state of every particle's material at its current mass density
+/// Hot particles are evaluated as if they were denser by their thermal expansion, so they push
+/// their neighbors away until the fluid around them is light enough to rise.
+pub fn calc_pressure(
+    mut particles: Query<
+        (
+            &mut Pressure,
+            &LocalMassDensity,
+            &Mass,
+            &MaterialId,
+            &Temperature,
+        ),
+        With<Particle>,
+    >,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    materials: Res<FluidMaterials>,
+    thermal: Res<ThermalParameters>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    let rest_density = rest_number_density(&**kernel, &entity_lookup_chunk);
+    for (mut pressure, mass_density, mass, material, temperature) in particles.iter_mut() {
+        let equation_of_state = materials.equation_of_state(*material, params.equation_of_state);
+        let density = mass_density.0 * thermal.expansion_factor(temperature.0);
+        pressure.0 = equation_of_state.pressure(density, rest_density * mass.0);
+        // Grains push each other apart but never pull
+        if materials.granular(*material).is_some() {
+            pressure.0 = pressure.0.max(0.0);
+        }
+    }
+}
+
 pub fn calc_pressure_force(
-    mut particles: Query<(&mut Acceleration, &LocalMassDensity, &Transform), With<Particle>>,
-    read_particles: Query<(&Transform, &LocalMassDensity, &Mass), With<Particle>>,
+    mut particles: Query<
+        (
+            &mut Acceleration,
+            &LocalMassDensity,
+            &Pressure,
+            &Mass,
+            &PredictedPos,
+        ),
+        With<Particle>,
+    >,
+    read_particles: Query<(&PredictedPos, &LocalMassDensity, &Mass, &Pressure), With<Particle>>,
+    obstacles: Query<(&Obstacle, &Transform), Without<Particle>>,
     entity_lookup_chunk: Res<EntityLookupChunk>,
     params: Res<SimParameters>,
+    kernel: Res<Kernel>,
 ) {
-    for (mut acc, mass_density, pos) in particles.iter_mut() {
-        let mut rng = rand::thread_rng();
-        let pos = Vec2::new(
-            pos.translation.x, // + (rng.gen::<f32>() - 0.5) * 0.1,
-            pos.translation.y, // + (rng.gen::<f32>() - 0.5) * 0.1,
+    for (mut acc, mass_density, pressure, mass, predicted) in particles.iter_mut() {
+        let pos = predicted.0;
+        let own_term = pressure.0 / (mass_density.0 * mass_density.0);
+        let particle_acc = get_particle_pressure_gradient(
+            pos,
+            own_term,
+            &read_particles,
+            &entity_lookup_chunk,
+            &**kernel,
+        )
+        .unwrap_or_default();
+        let obstacle_force = get_obstacle_pressure_gradient(
+            pos,
+            pressure.0,
+            mass_density.0,
+            mass.0,
+            obstacles
+                .iter()
+                .map(|(obstacle, transform)| (&obstacle.shape, transform)),
+            &**kernel,
         );
-        let pressure_force =
-            get_particle_pressure_gradient(pos, &read_particles, &entity_lookup_chunk, &params)
-                .unwrap_or_default();
         // NOTE: usize mass_density because here it is the "local" mass
-        acc.0.x = pressure_force.x / mass_density.0;
-        acc.0.y = pressure_force.y / mass_density.0 - params.gravity;
+        let obstacle_acc = obstacle_force / mass_density.0;
+        acc.0.x = particle_acc.x + obstacle_acc.x;
+        acc.0.y = particle_acc.y + obstacle_acc.y - params.gravity;
         //let len = acc.0.length();
         //acc.0.clamp_length_max(len * 0.9);
     }
 }
 
+/// Mass density as the particle's own mass times the number density of its neighborhood
+/// (Solenthaler and Pajarola 2008). Summing the neighbors' masses instead would overestimate the
+/// density of light particles next to heavy ones, and the spurious pressure at the interface
+/// would mix the phases.
 pub fn calc_local_mass_density(
-    mut write_particles: Query<(&mut LocalMassDensity, &Transform), With<Particle>>,
-    read_particles: Query<(&Transform, &Mass), With<Particle>>,
+    mut write_particles: Query<
+        (
+            &mut LocalMassDensity,
+            &mut NearDensity,
+            &PredictedPos,
+            &Mass,
+        ),
+        With<Particle>,
+    >,
+    read_particles: Query<&PredictedPos, With<Particle>>,
     entity_lookup_chunk: Res<EntityLookupChunk>,
+    kernel: Res<Kernel>,
 ) {
-    for (mut local_density, transform) in write_particles.iter_mut() {
-        let mass_density = get_particle_mass_density(
-            Vec2::new(transform.translation.x, transform.translation.y),
+    let near_kernel = kernel.near();
+    for (mut local_density, mut near_density, predicted, mass) in write_particles.iter_mut() {
+        let (number_density, near) = get_particle_densities(
+            predicted.0,
             &read_particles,
             &entity_lookup_chunk,
-        )
-        .unwrap_or_default();
+            &**kernel,
+            &near_kernel,
+        );
+        let mass_density = number_density * mass.0;
         local_density.0 = mass_density;
+        near_density.0 = near * mass.0;
         // println!("LMD {mass_density}");
     }
 }
 
+/// Number density and near number density in a single pass over the neighbors
+fn get_particle_densities(
+    at_pos: Vec2,
+    particles: &Query<&PredictedPos, With<Particle>>,
+    entity_lookup_chunk: &EntityLookupChunk,
+    kernel: &dyn SmoothingKernel,
+    near_kernel: &Spiky,
+) -> (f32, f32) {
+    let chunk_pos = entity_lookup_chunk.chunk_position(at_pos.x, at_pos.y);
+    let mut density = 0.0;
+    let mut near_density = 0.0;
+    for entity in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+        let predicted = particles
+            .get(entity)
+            .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+        let distance = predicted.0.distance(at_pos);
+        if distance >= kernel.radius() {
+            continue;
+        }
+        density += kernel.value(distance);
+        near_density += near_kernel.value(distance);
+    }
+    (density, near_density)
+}
+
+/// Clavet style near pressure `k_near ρ_near` with the same symmetric form as the pressure
+/// force. It only ever repels and grows steeply at short range. The incompressible solvers add
+/// it to the other forces ahead of their pressure solve.
+pub fn calc_near_pressure_force(
+    particles: Query<
+        (
+            Entity,
+            &PredictedPos,
+            &LocalMassDensity,
+            &NearDensity,
+            &Mass,
+        ),
+        With<Particle>,
+    >,
+    mut accelerations: Query<&mut Acceleration, With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    if params.near_pressure <= 0.0 {
+        return;
+    }
+    let near_kernel = kernel.near();
+    let mut near_accelerations = Vec::new();
+    for (entity, predicted, density, near_density, _) in particles.iter() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+        let own_term = params.near_pressure * near_density.0 / (density.0 * density.0);
+
+        let mut acc = Vec2::ZERO;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            if other == entity {
+                continue;
+            }
+            let (_, other_predicted, other_density, other_near_density, other_mass) = particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let other_term =
+                params.near_pressure * other_near_density.0 / (other_density.0 * other_density.0);
+            acc -= near_kernel.gradient_at(diff) * other_mass.0 * (own_term + other_term);
+        }
+        if acc.is_finite() {
+            near_accelerations.push((entity, acc));
+        }
+    }
+    for (entity, near_acc) in near_accelerations {
+        if let Ok(mut acc) = accelerations.get_mut(entity) {
+            acc.0 += near_acc;
+        }
+    }
+}
+
+/// Moves `PredictedPos` to where the integrator wants the forces of this step evaluated. The
+/// acceleration of the previous step is kept around for the kick.
 pub fn calc_pred_pos(
-    mut particle_q: Query<(&mut PredictedPos, &Transform, &Velocity), With<Particle>>,
+    mut particle_q: Query<
+        (
+            &mut PredictedPos,
+            &Transform,
+            &mut Velocity,
+            &Acceleration,
+            &mut PreviousAcceleration,
+        ),
+        With<Particle>,
+    >,
+    params: Res<SimParameters>,
+    integrator: Res<Integrator>,
+) {
+    let dt = params.step_dt();
+    for (mut pred, transf, mut vel, acc, mut previous_acc) in particle_q.iter_mut() {
+        let (pos, predicted_vel) =
+            integrator.predict(transf.translation.truncate(), vel.0, acc.0, dt);
+        pred.0 = pos;
+        vel.0 = predicted_vel;
+        previous_acc.0 = acc.0;
+    }
+}
+
+/// Solvers that evaluate forces at the start of the step begin with the predicted positions there
+pub fn reset_predicted_positions(
+    mut particles: Query<(&mut PredictedPos, &Transform), With<Particle>>,
 ) {
-    for (mut pred, transf, &Velocity(vel)) in particle_q.iter_mut() {
-        let pos = &mut pred.0;
-        pos.x = transf.translation.x + vel.x;
-        pos.y = transf.translation.y + vel.y;
+    for (mut predicted, transform) in particles.iter_mut() {
+        predicted.0 = transform.translation.truncate();
     }
 }
 
+/// Starts the accelerations of the step with gravity, for solvers that do not use
+/// `calc_pressure_force`
+pub fn calc_gravity_force(
+    mut particles: Query<&mut Acceleration, With<Particle>>,
+    params: Res<SimParameters>,
+) {
+    for mut acc in particles.iter_mut() {
+        acc.0 = Vec2::new(0.0, -params.gravity);
+    }
+}
+
+/// Moves the predicted positions ahead with the current velocities
+pub fn drift_predicted_positions(
+    mut particles: Query<(&mut PredictedPos, &Transform, &Velocity), With<Particle>>,
+    params: Res<SimParameters>,
+) {
+    let dt = params.step_dt();
+    for (mut predicted, transform, vel) in particles.iter_mut() {
+        predicted.0 = transform.translation.truncate() + vel.0 * dt;
+    }
+}
+
+/// Moves the particles onto their predicted positions, resolving collisions on the way. Used by
+/// the solvers that work on positions directly instead of going through the `Integrator`.
+pub fn commit_predicted_positions(
+    mut particles: Query<(&mut Transform, &mut Velocity, &PredictedPos, &Mass), With<Particle>>,
+    obstacles: Query<(&Obstacle, &Transform, &ObstacleMotion), Without<Particle>>,
+    mut bodies: Query<(&mut FluidRigidBody, &Transform), Without<Particle>>,
+    domain: Res<SimDomain>,
+) {
+    for (mut pos, mut vel, predicted, mass) in particles.iter_mut() {
+        let (moved, moved_vel) = collide_obstacles(predicted.0, vel.0, obstacles.iter());
+        let (moved, moved_vel) = collide_rigid_bodies(moved, moved_vel, mass.0, bodies.iter_mut());
+        let (new_pos, new_vel) = domain.collide(moved, moved_vel);
+        vel.0 = new_vel;
+
+        pos.translation.x = new_pos.x;
+        pos.translation.y = new_pos.y;
+    }
+}
+
+/// Re-buckets every particle at its predicted position, which all forces are evaluated at
+pub fn update_chunk_positions(
+    mut particles: Query<(Entity, &PredictedPos, &mut ChunkPosition), With<Particle>>,
+    mut entity_lookup_chunk: ResMut<EntityLookupChunk>,
+) {
+    for (entity, predicted, mut chunk_pos) in particles.iter_mut() {
+        entity_lookup_chunk.relocate(entity, &mut chunk_pos, predicted.0.x, predicted.0.y);
+    }
+}
+
+#[allow(dead_code)]
 pub fn get_particle_density(
     at_pos: Vec2,
-    particles: &Query<&Transform, With<Particle>>,
+    particles: &Query<&PredictedPos, With<Particle>>,
     entity_lookup_chunk: &EntityLookupChunk,
+    kernel: &dyn SmoothingKernel,
 ) -> Option<f32> {
-    let chunk_pos: UVec2 = Chunk::<Vec<Entity>>::get_chunk_pos(at_pos.x, at_pos.y)?;
-    let chunk_x = chunk_pos.x as usize;
-    let chunk_y = chunk_pos.y as usize;
-    //println!("Chunk_pos: {}|{}", chunk_x, chunk_y);
-
-    let entities = entity_lookup_chunk.get_neighborhood_entities(chunk_x, chunk_y);
+    let chunk_pos = entity_lookup_chunk.chunk_position(at_pos.x, at_pos.y);
+    let entities = entity_lookup_chunk.get_neighborhood_entities(&chunk_pos);
 
     let mut density = 0.0;
     for entity in entities.iter() {
         //println!("Entity: {}", entity);
-        let transf = particles
+        let predicted = particles
             .get(*entity)
             .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
 
-        let x_diff = transf.translation.x - at_pos.x;
-        let y_diff = transf.translation.y - at_pos.y;
-        let dist_sq = x_diff * x_diff + y_diff * y_diff;
-        if dist_sq >= 1.0 {
+        let x_diff = predicted.0.x - at_pos.x;
+        let y_diff = predicted.0.y - at_pos.y;
+        let distance = f32::sqrt(x_diff * x_diff + y_diff * y_diff);
+        if distance >= kernel.radius() {
             continue;
         }
-        let distance = f32::sqrt(dist_sq);
-        let influence = distance_density_influence(distance);
+        let influence = kernel.value(distance);
         density += influence;
         //println!("Influence: {}", influence);
     }
@@ -333,185 +974,230 @@ pub fn get_particle_density(
 }
 pub fn get_particle_mass_density(
     at_pos: Vec2,
-    particles: &Query<(&Transform, &Mass), With<Particle>>,
+    particles: &Query<(&PredictedPos, &Mass), With<Particle>>,
     entity_lookup_chunk: &EntityLookupChunk,
+    kernel: &dyn SmoothingKernel,
 ) -> Option<f32> {
-    let chunk_pos: UVec2 = Chunk::<Vec<Entity>>::get_chunk_pos(at_pos.x, at_pos.y)?;
-    let chunk_x = chunk_pos.x as usize;
-    let chunk_y = chunk_pos.y as usize; //println!("Chunk_pos: {}|{}", chunk_x, chunk_y);
-    let entities = entity_lookup_chunk.get_neighborhood_entities(chunk_x, chunk_y);
+    let chunk_pos = entity_lookup_chunk.chunk_position(at_pos.x, at_pos.y);
+    let entities = entity_lookup_chunk.get_neighborhood_entities(&chunk_pos);
 
     let mut density = 0.0;
     for entity in entities.iter() {
         //println!("Entity: {}", entity);
-        let (transf, mass) = particles
+        let (predicted, mass) = particles
             .get(*entity)
             .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
 
-        let x_diff = transf.translation.x - at_pos.x;
-        let y_diff = transf.translation.y - at_pos.y;
-        let dist_sq = x_diff * x_diff + y_diff * y_diff;
-        if dist_sq >= 1.0 {
+        let x_diff = predicted.0.x - at_pos.x;
+        let y_diff = predicted.0.y - at_pos.y;
+        let distance = f32::sqrt(x_diff * x_diff + y_diff * y_diff);
+        if distance >= kernel.radius() {
             continue;
         }
-        let distance = f32::sqrt(dist_sq);
-        let influence = distance_density_influence(distance);
+        let influence = kernel.value(distance);
         density += influence * mass.0;
         //println!("Influence: {}", influence);
     }
     Some(density)
 }
 
-pub const DAMPENING: f32 = 0.7;
 pub fn update_particle_pos(
-    mut particles: Query<
-        (Entity, &mut Transform, &mut Velocity, &mut ChunkPosition),
-        With<Particle>,
-    >,
-    mut entity_lookup_chunk: ResMut<EntityLookupChunk>,
+    mut particles: Query<(&mut Transform, &mut Velocity, &PredictedPos, &Mass), With<Particle>>,
+    obstacles: Query<(&Obstacle, &Transform, &ObstacleMotion), Without<Particle>>,
+    mut bodies: Query<(&mut FluidRigidBody, &Transform), Without<Particle>>,
+    domain: Res<SimDomain>,
+    params: Res<SimParameters>,
+    integrator: Res<Integrator>,
 ) {
-    for (entity, mut pos, mut vel, mut chunk_pos) in particles.iter_mut() {
-        let mut new_x = pos.translation.x + vel.0.x;
-        // println!("Vel: {}|{}", vel.0.x, vel.0.y);
-        if new_x < 0.5 {
-            new_x = 0.5;
-            vel.0.x *= -DAMPENING;
-        } else if new_x > (CHUNK_SIZE - 1) as f32 {
-            new_x = (CHUNK_SIZE - 1) as f32;
-            vel.0.x *= -DAMPENING;
-        }
-
-        let mut new_y = pos.translation.y + vel.0.y;
-        if new_y < 0.5 {
-            new_y = 0.5;
-            vel.0.y *= -DAMPENING;
-        } else if new_y > (CHUNK_SIZE - 1) as f32 {
-            new_y = (CHUNK_SIZE - 1) as f32;
-            vel.0.y *= -DAMPENING;
-        }
-
-        pos.translation.x = new_x;
-        pos.translation.y = new_y;
-
-        // println!("{}|{}", new_x, new_y);
-        let previous = chunk_pos.pos.clone();
-        // println!("ChunkPos {}|{}", chunk_pos.pos.x, chunk_pos.pos.y);
-
-        chunk_pos.pos = Chunk::<Vec<Entity>>::get_chunk_pos(new_x, new_y)
-            .expect("Particle is not allowed outside bounds");
+    let dt = params.step_dt();
+    for (mut pos, mut vel, predicted, mass) in particles.iter_mut() {
+        let moved = integrator.drift(pos.translation.truncate(), predicted.0, vel.0, dt);
+        let (moved, moved_vel) = collide_obstacles(moved, vel.0, obstacles.iter());
+        let (moved, moved_vel) = collide_rigid_bodies(moved, moved_vel, mass.0, bodies.iter_mut());
+        let (new_pos, new_vel) = domain.collide(moved, moved_vel);
+        vel.0 = new_vel;
 
-        if previous != chunk_pos.pos {
-            let mut prev_cell = entity_lookup_chunk
-                .0
-                .get_mut(previous.x as usize, previous.y as usize)
-                .expect("All Cell positions should be valid at all times");
-            prev_cell.remove(&entity);
-
-            entity_lookup_chunk.insert(entity, chunk_pos.pos.x as usize, chunk_pos.pos.y as usize);
-        }
+        pos.translation.x = new_pos.x;
+        pos.translation.y = new_pos.y;
     }
 }
 
+/// Symmetric pressure acceleration `-Σ m_j (p_i / ρ_i² + p_j / ρ_j²) ∇W` at `at_pos`, where
+/// `own_term` is `p_i / ρ_i²` of the particle there. Every pair pushes with equal and opposite
+/// forces, so the pressure conserves momentum. Coincident particles are skipped; the gradient
+/// between them is zero anyway.
 pub fn get_particle_pressure_gradient(
     at_pos: Vec2,
-    particles: &Query<(&Transform, &LocalMassDensity, &Mass), With<Particle>>,
+    own_term: f32,
+    particles: &Query<(&PredictedPos, &LocalMassDensity, &Mass, &Pressure), With<Particle>>,
     entity_lookup_chunk: &EntityLookupChunk,
-    params: &SimParameters,
+    kernel: &dyn SmoothingKernel,
 ) -> Option<Vec2> {
-    let chunk_pos: UVec2 = Chunk::<Vec<Entity>>::get_chunk_pos(at_pos.x, at_pos.y)?;
-    let chunk_x = chunk_pos.x as usize;
-    let chunk_y = chunk_pos.y as usize; //println!("Chunk_pos: {}|{}", chunk_x, chunk_y);
-    let entities = entity_lookup_chunk.get_neighborhood_entities(chunk_x, chunk_y);
-
-    let mut rng = rand::thread_rng();
+    let chunk_pos = entity_lookup_chunk.chunk_position(at_pos.x, at_pos.y);
+    let entities = entity_lookup_chunk.get_neighborhood_entities(&chunk_pos);
 
     let mut result = Vec2::ZERO;
     for entity in entities.iter() {
-        let (transf, mass_density, mass) =
+        let (predicted, mass_density, mass, pressure) =
             particles.get(*entity).expect("Entity not found in query");
-        let particle_pos = Vec2::new(transf.translation.x, transf.translation.y);
+        let particle_pos = predicted.0;
         let diff = Vec2::new(particle_pos.x - at_pos.x, particle_pos.y - at_pos.y);
         let dist = diff.length();
-        if dist >= INFLUENCE_RADIUS {
-            continue;
-        }
-        if dist <= 0.000001 {
-            result = result.mul_add(
-                Vec2::ONE,
-                Vec2::new(
-                    rng.gen::<f32>() * 0.01 - 0.005,
-                    rng.gen::<f32>() * 0.01 - 0.005,
-                ),
-            );
+        if dist >= kernel.radius() || dist <= 0.000001 {
             continue;
         }
-        // println!("Diff: ", )
-        let direction = diff.normalize_or_zero();
-        // NOTE: It might be easier to just leave density out entirely and instead rely on
-        // particle_density???;
-        // let influence = mass.0 / pressure_from_density(mass_density.0, &pressure_mult);
-        let influence = pressure_from_density(mass_density.0, &params) / mass_density.0 * mass.0;
-        // let influence = mass.0 / mass_density.0 * 0.1;
-
-        let derivative = distance_density_derivative(dist);
-        let derivative_vector = Vec2::new(
-            -direction.x * derivative * influence,
-            -direction.y * derivative * influence,
-        );
+        let other_term = pressure.0 / (mass_density.0 * mass_density.0);
+        let influence = (own_term + other_term) * mass.0;
+
+        let derivative_vector = -kernel.gradient_at(-diff) * influence;
         // println!("Single deriv: {derivative_vector:?}");
         result = result.mul_add(Vec2::ONE, derivative_vector);
     }
     Some(result)
 }
 
-pub const INFLUENCE_RADIUS: f32 = 1.0; // exactly 1 tile in each direction
-pub const INFLUENCE_VOLUME: f32 = 1.0;
-pub fn distance_density_influence(distance: f32) -> f32 {
-    if !(0.0..=INFLUENCE_RADIUS).contains(&distance) {
-        return 0.0;
+/// Pressure gradient from mirrored ghost particles behind nearby obstacle surfaces, so the
+/// fluid gets pushed away before it actually touches the obstacle
+pub fn get_obstacle_pressure_gradient<'a>(
+    at_pos: Vec2,
+    pressure: f32,
+    mass_density: f32,
+    mass: f32,
+    shapes: impl Iterator<Item = (&'a ObstacleShape, &'a Transform)>,
+    kernel: &dyn SmoothingKernel,
+) -> Vec2 {
+    let influence = pressure / mass_density * mass;
+    let mut result = Vec2::ZERO;
+    for (shape, transform) in shapes {
+        if !shape.is_near(transform, at_pos, kernel.radius()) {
+            continue;
+        }
+        let contact = shape.probe(transform, at_pos);
+        // The ghost sits mirrored on the other side of the surface
+        let ghost_dist = 2.0 * contact.distance.max(0.0);
+        if ghost_dist >= kernel.radius() {
+            continue;
+        }
+        result -= kernel.gradient_at(contact.normal * ghost_dist) * influence;
     }
-    let x = INFLUENCE_RADIUS - distance;
+    result
+}
 
-    x * x * x / INFLUENCE_VOLUME
+/// Number density of the particles at rest: a lattice with the spawn spacing, which is the grid
+/// cell size. It does not depend on the particle masses, so heavy and light materials keep the
+/// same spacing.
+pub fn rest_number_density(
+    kernel: &dyn SmoothingKernel,
+    entity_lookup_chunk: &EntityLookupChunk,
+) -> f32 {
+    lattice_density(kernel, entity_lookup_chunk.cell_size())
 }
 
-// The derivative will be needed to determine the speed with which the particles desire to leave
-// the dense areas
-pub fn distance_density_derivative(distance: f32) -> f32 {
-    if !(0.0..=INFLUENCE_RADIUS).contains(&distance) {
-        return 0.0;
+/// Keeps the grid from degenerating into millions of cells
+pub const MIN_SMOOTHING_RADIUS: f32 = 0.1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Semi-implicit Euler on the predicted positions, without collisions or jitter
+    fn advance(
+        mut particles: Query<(&mut PredictedPos, &mut Velocity, &Acceleration), With<Particle>>,
+        params: Res<SimParameters>,
+    ) {
+        let dt = params.step_dt();
+        for (mut predicted, mut vel, acc) in particles.iter_mut() {
+            vel.0 += acc.0 * dt;
+            predicted.0 += vel.0 * dt;
+        }
     }
 
-    if distance == 0.0 {
-        return 0.0;
+    /// Total momentum and the sum of the momentum magnitudes, which the error is measured against
+    fn momentum(world: &mut World) -> (Vec2, f32) {
+        world
+            .query_filtered::<(&Velocity, &Mass), With<Particle>>()
+            .iter(world)
+            .fold((Vec2::ZERO, 0.0), |(total, scale), (vel, mass)| {
+                (total + vel.0 * mass.0, scale + (vel.0 * mass.0).length())
+            })
     }
 
-    // NOTE: f(x) = (r - |x|)^3
-    // --> f(x) = u(x)^3
-    // --> u(x) = r - |x|
-    // f'(x) = 3u(x)^2 * u'(x)
-    // u'(x) = r' - |x|'
-    //       = 0 - sign(x)
-    //       = -sign(x)
-    // f'(x) = 3(r - |x|)^2 * -sign(x)
-    //
-    // for this function though, we can ignore the sign since the sign is reintroduced with the
-    // vector-difference
+    #[test]
+    fn pressure_conserves_momentum() {
+        let mut world = World::new();
+        world.insert_resource(Kernel::new(KernelKind::default(), 1.0));
+        // Near pressure is switched on so both pressure terms are covered
+        world.insert_resource(SimParameters {
+            near_pressure: 360.0,
+            ..default()
+        });
+        world.init_resource::<FluidMaterials>();
+        world.init_resource::<ThermalParameters>();
 
-    3.0 * (INFLUENCE_RADIUS - distance).powi(2)
-}
+        // A compressed block of mixed materials far from any wall, so the pressure pushes it apart
+        world.insert_resource(EntityLookupChunk::new(1.0));
+        for x in 0..12 {
+            for y in 0..12 {
+                let jitter = ((x * 7 + y * 3) % 5) as f32 * 0.03;
+                let pos = Vec2::new(x as f32 * 0.8 + jitter, y as f32 * 0.8 - jitter);
+                let material = ((x + y) % 3) as usize;
+                let chunk_position = world
+                    .resource::<EntityLookupChunk>()
+                    .chunk_position(pos.x, pos.y);
+                world.spawn((
+                    Particle,
+                    PredictedPos(pos),
+                    Velocity::default(),
+                    Acceleration::default(),
+                    Mass((material * 3 + 1) as f32 * 0.64),
+                    LocalMassDensity::default(),
+                    NearDensity::default(),
+                    Pressure::default(),
+                    Temperature::default(),
+                    MaterialId(material),
+                    chunk_position,
+                ));
+            }
+        }
 
-pub const TARGET_DENSITY: f32 = 0.0;
+        let mut schedule = Schedule::default();
+        schedule.add_systems(
+            (
+                update_chunk_positions,
+                calc_local_mass_density,
+                calc_pressure,
+                calc_pressure_force,
+                calc_near_pressure_force,
+                advance,
+            )
+                .chain(),
+        );
+        for _ in 0..60 {
+            schedule.run(&mut world);
+        }
 
-pub fn pressure_from_density(density: f32, params: &SimParameters) -> f32 {
-    let error = density - TARGET_DENSITY;
-    let mut val = error * params.pressure_mult;
-    if val.is_nan() {
-        val = 0.0;
+        let (total, scale) = momentum(&mut world);
+        assert!(
+            scale > 1.0,
+            "the block did not expand, momentum scale {scale}"
+        );
+        assert!(
+            total.length() < scale * 1e-4,
+            "momentum drifted to {total} against a scale of {scale}"
+        );
     }
-    if val.abs() <= 0.01 {
-        val.signum() * 0.01
-    } else {
-        val
+
+    #[test]
+    fn viscosity_limits_the_step() {
+        let params = SimParameters::default();
+        assert_eq!(params.cfl_step(1.0, 0.0, 0.0, 0.0), params.max_step);
+
+        let viscous = params.cfl_step(1.0, 0.0, 0.0, 30.0);
+        assert!((viscous - VISCOUS_STEP_FACTOR / 30.0).abs() < 1e-6);
+        // Twice the radius allows four times the step
+        let coarse = params.cfl_step(2.0, 0.0, 0.0, 120.0);
+        assert!((coarse - viscous).abs() < 1e-6);
+        // Never below the lower bound, however viscous
+        assert_eq!(params.cfl_step(1.0, 0.0, 0.0, 1e6), params.min_step);
     }
 }
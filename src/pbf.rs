@@ -0,0 +1,238 @@
+use crate::chunk::EntityLookupChunk;
+use crate::domain::SimDomain;
+use crate::kernel::{Kernel, SmoothingKernel};
+use crate::obstacle::{collide_obstacles, Obstacle, ObstacleMotion};
+use crate::particle::{
+    rest_number_density, Acceleration, LocalMassDensity, Mass, Particle, PredictedPos, Pressure,
+    SimParameters, Velocity,
+};
+use crate::thermal::{Temperature, ThermalParameters};
+use crate::vorticity::{vorticity_confinement, Vorticity};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+/// Settings of the Position Based Fluids solver
+#[derive(Resource, Clone, Debug)]
+pub struct PbfParameters {
+    /// Jacobi iterations of the density constraint per step
+    pub iterations: u32,
+    /// Constraint force mixing; softens the constraint and keeps lambda finite for particles with
+    /// hardly any neighbors
+    pub relaxation: f32,
+    /// Strength `k` of the artificial pressure that keeps particles from clustering
+    pub tensile_strength: f32,
+    /// Exponent `n` of the artificial pressure
+    pub tensile_exponent: i32,
+    /// Distance `Δq` of the artificial pressure, as a fraction of the smoothing radius
+    pub tensile_distance: f32,
+}
+
+impl Default for PbfParameters {
+    fn default() -> Self {
+        Self {
+            iterations: 4,
+            relaxation: 0.5,
+            tensile_strength: 0.1,
+            tensile_exponent: 4,
+            tensile_distance: 0.2,
+        }
+    }
+}
+
+/// Lagrange multiplier of the density constraint of a particle, recomputed every iteration. Once
+/// the solve is done it holds the sum over all iterations of the step.
+#[derive(Component, Default, Clone, Debug)]
+pub struct DensityLambda(pub f32);
+
+/// Applies the accumulated accelerations and moves the predicted positions ahead
+pub fn pbf_predict(
+    mut particles: Query<
+        (&mut PredictedPos, &mut Velocity, &Acceleration, &Transform),
+        With<Particle>,
+    >,
+    params: Res<SimParameters>,
+) {
+    let dt = params.step_dt();
+    for (mut predicted, mut vel, acc, transform) in particles.iter_mut() {
+        vel.0 += acc.0 * dt;
+        predicted.0 = transform.translation.truncate() + vel.0 * dt;
+    }
+}
+
+/// Jacobi iterations of the density constraint `C_i = δ_i / δ_0 - 1` on the predicted positions,
+/// with the artificial pressure term `s_corr` against clustering. Only compression is corrected,
+/// so particles at the free surface are not pulled towards the missing neighbors. Warm particles
+/// take more room, their `δ_0` shrinks by the thermal expansion factor.
+pub fn pbf_solve_density(
+    mut particles: Query<
+        (Entity, &mut PredictedPos, &mut DensityLambda, &Temperature),
+        With<Particle>,
+    >,
+    obstacles: Query<(&Obstacle, &Transform, &ObstacleMotion), Without<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    domain: Res<SimDomain>,
+    pbf: Res<PbfParameters>,
+    thermal: Res<ThermalParameters>,
+    kernel: Res<Kernel>,
+) {
+    let h = kernel.radius();
+    let lattice_density = rest_number_density(&**kernel, &entity_lookup_chunk);
+    let tensile_reference = kernel.value(pbf.tensile_distance * h);
+
+    let mut totals: HashMap<Entity, f32> = HashMap::default();
+    for _ in 0..pbf.iterations {
+        let mut lambdas = Vec::new();
+        for (entity, predicted, _, temperature) in particles.iter() {
+            let rest_density = lattice_density / thermal.expansion_factor(temperature.0);
+            let pos = predicted.0;
+            let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+            let mut density = kernel.value(0.0);
+            let mut own_gradient = Vec2::ZERO;
+            let mut gradient_sq = 0.0;
+            for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+                if other == entity {
+                    continue;
+                }
+                let (_, other_predicted, _, _) = particles
+                    .get(other)
+                    .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+                let diff = pos - other_predicted.0;
+                let dist = diff.length();
+                if dist >= h || dist <= 0.000001 {
+                    continue;
+                }
+                density += kernel.value(dist);
+                let gradient = kernel.gradient_at(diff) / rest_density;
+                own_gradient += gradient;
+                gradient_sq += gradient.length_squared();
+            }
+            gradient_sq += own_gradient.length_squared();
+
+            let constraint = (density / rest_density - 1.0).max(0.0);
+            lambdas.push((entity, -constraint / (gradient_sq + pbf.relaxation)));
+        }
+        for (entity, lambda) in lambdas {
+            if let Ok((_, _, mut particle_lambda, _)) = particles.get_mut(entity) {
+                particle_lambda.0 = lambda;
+                *totals.entry(entity).or_default() += lambda;
+            }
+        }
+
+        let mut corrections = Vec::new();
+        for (entity, predicted, lambda, temperature) in particles.iter() {
+            let rest_density = lattice_density / thermal.expansion_factor(temperature.0);
+            let pos = predicted.0;
+            let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+            let mut correction = Vec2::ZERO;
+            for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+                if other == entity {
+                    continue;
+                }
+                let (_, other_predicted, other_lambda, _) = particles
+                    .get(other)
+                    .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+                let diff = pos - other_predicted.0;
+                let dist = diff.length();
+                if dist >= h || dist <= 0.000001 {
+                    continue;
+                }
+                let tensile = tensile_correction(&**kernel, dist, tensile_reference, &pbf);
+                correction += kernel.gradient_at(diff) * (lambda.0 + other_lambda.0 + tensile);
+            }
+            let corrected = pos + correction / rest_density;
+            // Constraint projection has no velocity yet, only positions are kept out of solids
+            let (corrected, _) = collide_obstacles(corrected, Vec2::ZERO, obstacles.iter());
+            let (corrected, _) = domain.collide(corrected, Vec2::ZERO);
+            if corrected.is_finite() {
+                corrections.push((entity, corrected));
+            }
+        }
+        for (entity, corrected) in corrections {
+            if let Ok((_, mut predicted, _, _)) = particles.get_mut(entity) {
+                predicted.0 = corrected;
+            }
+        }
+    }
+    for (entity, total) in totals {
+        if let Ok((_, _, mut lambda, _)) = particles.get_mut(entity) {
+            lambda.0 = total;
+        }
+    }
+}
+
+/// Turns the multipliers of the step into the pressure the rigid bodies, obstacles and granular
+/// friction see from the next step on. The projection moves a particle by `λ_i ∇W / δ_0` where the
+/// pressure acceleration `-Σ m_j (p_i / ρ_i² + p_j / ρ_j²) ∇W` would move it by that times `dt²`,
+/// so `p_i = -λ_i ρ_0 / dt²` with the rest mass density `ρ_0 = m_i δ_0`.
+pub fn pbf_store_pressure(
+    mut particles: Query<(&DensityLambda, &Mass, &Temperature, &mut Pressure), With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    thermal: Res<ThermalParameters>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    let dt = params.step_dt();
+    if dt <= 0.0 {
+        return;
+    }
+    let lattice_density = rest_number_density(&**kernel, &entity_lookup_chunk);
+    for (lambda, mass, temperature, mut pressure) in particles.iter_mut() {
+        let rest_density = lattice_density * mass.0 / thermal.expansion_factor(temperature.0);
+        pressure.0 = -lambda.0 * rest_density / (dt * dt);
+    }
+}
+
+/// Artificial pressure `s_corr = -k (W(r) / W(Δq))^n`
+fn tensile_correction(
+    kernel: &dyn SmoothingKernel,
+    distance: f32,
+    reference: f32,
+    pbf: &PbfParameters,
+) -> f32 {
+    if reference <= 0.0 {
+        return 0.0;
+    }
+    -pbf.tensile_strength * (kernel.value(distance) / reference).powi(pbf.tensile_exponent)
+}
+
+/// The velocity is whatever moved the particle to its corrected position
+pub fn pbf_update_velocity(
+    mut particles: Query<(&mut Velocity, &PredictedPos, &Transform), With<Particle>>,
+    params: Res<SimParameters>,
+) {
+    let dt = params.step_dt();
+    if dt <= 0.0 {
+        return;
+    }
+    for (mut vel, predicted, transform) in particles.iter_mut() {
+        vel.0 = (predicted.0 - transform.translation.truncate()) / dt;
+    }
+}
+
+/// Puts back the rotation the position projection smoothed away, as a velocity kick from the
+/// vorticity of the corrected velocities
+pub fn pbf_vorticity_confinement(
+    particles: Query<(Entity, &PredictedPos, &LocalMassDensity, &Mass, &Vorticity), With<Particle>>,
+    mut velocities: Query<&mut Velocity, With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    if params.vorticity_confinement <= 0.0 {
+        return;
+    }
+    let dt = params.step_dt();
+    let forces = vorticity_confinement(
+        &particles,
+        &entity_lookup_chunk,
+        &kernel,
+        params.vorticity_confinement,
+    );
+    for (entity, force) in forces {
+        if let Ok(mut vel) = velocities.get_mut(entity) {
+            vel.0 += force * dt;
+        }
+    }
+}
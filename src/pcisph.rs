@@ -0,0 +1,253 @@
+use crate::chunk::EntityLookupChunk;
+use crate::domain::SimDomain;
+use crate::kernel::Kernel;
+use crate::obstacle::{collide_obstacles, Obstacle, ObstacleMotion};
+use crate::particle::{
+    rest_number_density, Acceleration, LocalMassDensity, Mass, Particle, PredictedPos, Pressure,
+    SimParameters, Velocity,
+};
+use crate::solver::SolverStats;
+use crate::thermal::{Temperature, ThermalParameters};
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::prelude::*;
+
+/// One prediction-correction iteration. [`run_pcisph_iterations`] repeats it until the density
+/// error is within tolerance.
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PcisphIteration;
+
+/// Settings of the PCISPH solver
+#[derive(Resource, Clone, Debug)]
+pub struct PcisphParameters {
+    /// Mean relative density error the iterations stop at
+    pub tolerance: f32,
+    pub min_iterations: u32,
+    /// Upper bound even if the tolerance was not reached, keeps a bad step from stalling the frame
+    pub max_iterations: u32,
+}
+
+impl Default for PcisphParameters {
+    fn default() -> Self {
+        Self {
+            tolerance: 0.01,
+            min_iterations: 3,
+            max_iterations: 50,
+        }
+    }
+}
+
+/// Pressure state of a particle during the PCISPH iterations
+#[derive(Component, Default, Clone, Debug)]
+pub struct PcisphPressure {
+    pub pressure: f32,
+    /// Acceleration from the current pressures, on top of the non-pressure `Acceleration`
+    pub acceleration: Vec2,
+    /// Pressure change per unit density error, without the `1 / dt²`
+    pub stiffness: f32,
+    /// Mass density this particle is driven towards
+    pub rest_density: f32,
+}
+
+/// Resets the pressures and precomputes the stiffness of every particle from its neighborhood at
+/// the start of the step, following the derivation of the PCISPH `δ` with the actual neighbors
+/// instead of a prototype particle. The density is `m_i Σ W_ij`, so its change under the pressure
+/// displacements is `m_i Σ (Δx_i - Δx_j) · ∇W_ij`. Warm particles take more room and are driven
+/// towards a lower rest density.
+pub fn pcisph_init(
+    mut particles: Query<
+        (
+            Entity,
+            &PredictedPos,
+            &Mass,
+            &Temperature,
+            &mut PcisphPressure,
+        ),
+        With<Particle>,
+    >,
+    read_particles: Query<(&PredictedPos, &Mass), With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    thermal: Res<ThermalParameters>,
+    kernel: Res<Kernel>,
+) {
+    let rest_density = rest_number_density(&**kernel, &entity_lookup_chunk);
+    for (entity, predicted, mass, temperature, mut state) in particles.iter_mut() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        let mut gradient_sum = Vec2::ZERO;
+        let mut mass_gradient_sum = Vec2::ZERO;
+        let mut gradient_sq = 0.0;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            if other == entity {
+                continue;
+            }
+            let (other_predicted, other_mass) = read_particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let gradient = kernel.gradient_at(diff);
+            gradient_sum += gradient;
+            mass_gradient_sum += gradient * other_mass.0;
+            gradient_sq += gradient.length_squared();
+        }
+        let denominator =
+            mass.0 * mass_gradient_sum.dot(gradient_sum) + mass.0 * mass.0 * gradient_sq;
+
+        state.rest_density = rest_density * mass.0 / thermal.expansion_factor(temperature.0);
+        state.stiffness = if denominator > f32::EPSILON {
+            state.rest_density * state.rest_density / (2.0 * denominator)
+        } else {
+            0.0
+        };
+        state.pressure = 0.0;
+        state.acceleration = Vec2::ZERO;
+    }
+}
+
+/// Runs the prediction-correction loop of a single step and records its convergence
+pub fn run_pcisph_iterations(world: &mut World) {
+    let settings = world.resource::<PcisphParameters>().clone();
+    let mut iterations = 0;
+    loop {
+        world.run_schedule(PcisphIteration);
+        iterations += 1;
+
+        let residual = world.resource::<SolverStats>().residual;
+        let converged = iterations >= settings.min_iterations && residual <= settings.tolerance;
+        if converged || iterations >= settings.max_iterations {
+            break;
+        }
+    }
+    world.resource_mut::<SolverStats>().iterations = iterations;
+}
+
+/// Predicts where every particle ends up with the current pressures, kept out of the domain
+/// walls and the obstacles like the final positions
+pub fn pcisph_predict(
+    mut particles: Query<
+        (
+            &mut PredictedPos,
+            &Transform,
+            &Velocity,
+            &Acceleration,
+            &PcisphPressure,
+        ),
+        With<Particle>,
+    >,
+    obstacles: Query<(&Obstacle, &Transform, &ObstacleMotion), Without<Particle>>,
+    domain: Res<SimDomain>,
+    params: Res<SimParameters>,
+) {
+    let dt = params.step_dt();
+    for (mut predicted, transform, vel, acc, state) in particles.iter_mut() {
+        let vel = vel.0 + (acc.0 + state.acceleration) * dt;
+        let (pos, vel) = domain.collide(transform.translation.truncate() + vel * dt, vel);
+        let (pos, _) = collide_obstacles(pos, vel, obstacles.iter());
+        predicted.0 = pos;
+    }
+}
+
+/// Corrects the pressures by the density error at the predicted positions. Only compression is
+/// corrected, particles at the free surface do not get pulled together.
+pub fn pcisph_correct_pressure(
+    mut particles: Query<(&LocalMassDensity, &mut PcisphPressure), With<Particle>>,
+    mut stats: ResMut<SolverStats>,
+    params: Res<SimParameters>,
+) {
+    let dt = params.step_dt();
+    let mut total_error = 0.0;
+    let mut max_error = 0.0f32;
+    let mut count = 0;
+    for (density, mut state) in particles.iter_mut() {
+        if state.rest_density <= 0.0 {
+            continue;
+        }
+        let error = density.0 - state.rest_density;
+        state.pressure = (state.pressure + state.stiffness / (dt * dt) * error).max(0.0);
+
+        let relative = (error / state.rest_density).max(0.0);
+        total_error += relative;
+        max_error = max_error.max(relative);
+        count += 1;
+    }
+    stats.residual = if count > 0 {
+        total_error / count as f32
+    } else {
+        0.0
+    };
+    stats.max_error = max_error;
+}
+
+/// Symmetric pressure acceleration `-Σ m_j (p_i / ρ_i² + p_j / ρ_j²) ∇W` at the predicted positions
+pub fn pcisph_pressure_force(
+    mut particles: Query<
+        (
+            Entity,
+            &PredictedPos,
+            &LocalMassDensity,
+            &Mass,
+            &mut PcisphPressure,
+        ),
+        With<Particle>,
+    >,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    kernel: Res<Kernel>,
+) {
+    let mut accelerations = Vec::new();
+    for (entity, predicted, density, _, state) in particles.iter() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+        let own_term = state.pressure / (density.0 * density.0);
+
+        let mut acc = Vec2::ZERO;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            if other == entity {
+                continue;
+            }
+            let (_, other_predicted, other_density, other_mass, other_state) = particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let other_term = other_state.pressure / (other_density.0 * other_density.0);
+            acc -= kernel.gradient_at(diff) * other_mass.0 * (own_term + other_term);
+        }
+        if acc.is_finite() {
+            accelerations.push((entity, acc));
+        }
+    }
+    for (entity, acc) in accelerations {
+        if let Ok((_, _, _, _, mut state)) = particles.get_mut(entity) {
+            state.acceleration = acc;
+        }
+    }
+}
+
+/// Applies the converged pressure together with the other forces
+pub fn pcisph_integrate(
+    mut particles: Query<(&mut Velocity, &mut Acceleration, &PcisphPressure), With<Particle>>,
+    params: Res<SimParameters>,
+) {
+    let dt = params.step_dt();
+    for (mut vel, mut acc, state) in particles.iter_mut() {
+        acc.0 += state.acceleration;
+        vel.0 += acc.0 * dt;
+    }
+}
+
+/// Hands the converged pressure to the rigid bodies, obstacles and granular friction, which see it
+/// from the next step on
+pub fn pcisph_store_pressure(
+    mut particles: Query<(&PcisphPressure, &mut Pressure), With<Particle>>,
+) {
+    for (state, mut pressure) in particles.iter_mut() {
+        pressure.0 = state.pressure;
+    }
+}
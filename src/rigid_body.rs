@@ -0,0 +1,322 @@
+use crate::chunk::EntityLookupChunk;
+use crate::domain::SimDomain;
+use crate::kernel::Kernel;
+use crate::obstacle::{ObstacleShape, COLLISION_MARGIN};
+use crate::particle::{
+    get_obstacle_pressure_gradient, Acceleration, LocalMassDensity, Mass, Particle, PredictedPos,
+    Pressure, SimParameters,
+};
+use bevy::prelude::*;
+
+/// A solid body that floats or sinks in the fluid. It receives the pressure of nearby particles
+/// and pushes them back with the opposite force, so momentum is exchanged in both directions.
+#[derive(Component, Clone, Debug)]
+#[require(Transform)]
+pub struct FluidRigidBody {
+    pub shape: ObstacleShape,
+    /// A body without positive mass is immovable
+    pub mass: f32,
+    /// Moment of inertia around the `Transform` origin. A body without positive inertia does not
+    /// rotate.
+    pub inertia: f32,
+    pub velocity: Vec2,
+    pub angular_velocity: f32,
+    /// Force and torque gathered from the fluid during the current step
+    pub force: Vec2,
+    pub torque: f32,
+}
+
+impl FluidRigidBody {
+    /// Body made of a uniform material, with mass and inertia derived from the shape. A zero
+    /// density or a degenerate shape gives an immovable body.
+    pub fn from_density(shape: ObstacleShape, density: f32) -> Self {
+        let (mass, inertia) = shape.mass_properties(density);
+        Self {
+            shape,
+            mass: non_negative(mass),
+            inertia: non_negative(inertia),
+            velocity: Vec2::ZERO,
+            angular_velocity: 0.0,
+            force: Vec2::ZERO,
+            torque: 0.0,
+        }
+    }
+
+    pub fn point_velocity(&self, offset: Vec2) -> Vec2 {
+        self.velocity + offset.perp() * self.angular_velocity
+    }
+
+    pub fn inverse_mass(&self) -> f32 {
+        inverse(self.mass)
+    }
+
+    pub fn inverse_inertia(&self) -> f32 {
+        inverse(self.inertia)
+    }
+
+    pub fn apply_impulse(&mut self, impulse: Vec2, offset: Vec2) {
+        self.velocity += impulse * self.inverse_mass();
+        self.angular_velocity += offset.perp_dot(impulse) * self.inverse_inertia();
+    }
+}
+
+fn non_negative(value: f32) -> f32 {
+    if value.is_finite() {
+        value.max(0.0)
+    } else {
+        0.0
+    }
+}
+
+/// `1 / value`, or 0 for values that are not positive and finite, e.g. the mass of a body with zero
+/// density or a degenerate shape
+fn inverse(value: f32) -> f32 {
+    if value > 0.0 && value.is_finite() {
+        1.0 / value
+    } else {
+        0.0
+    }
+}
+
+/// Pressure exchange between the fluid and every rigid body. Runs after the particle pressure
+/// forces so it can add onto their accelerations.
+pub fn couple_rigid_bodies(
+    mut bodies: Query<(&mut FluidRigidBody, &Transform), Without<Particle>>,
+    mut particles: Query<
+        (
+            &mut Acceleration,
+            &PredictedPos,
+            &LocalMassDensity,
+            &Pressure,
+            &Mass,
+        ),
+        With<Particle>,
+    >,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    for (mut body, body_transform) in bodies.iter_mut() {
+        let center = body_transform.translation.truncate();
+        let reach = body.shape.bounding_radius() * body_transform.scale.x.abs() + kernel.radius();
+        let entities = entity_lookup_chunk
+            .get_area_entities(center - Vec2::splat(reach), center + Vec2::splat(reach));
+
+        let mut force = Vec2::new(0.0, -body.mass * params.gravity);
+        let mut torque = 0.0;
+        for entity in entities {
+            let Ok((mut acc, predicted, mass_density, pressure, mass)) = particles.get_mut(entity)
+            else {
+                continue;
+            };
+            let pos = predicted.0;
+            let gradient = get_obstacle_pressure_gradient(
+                pos,
+                pressure.0,
+                mass_density.0,
+                mass.0,
+                std::iter::once((&body.shape, body_transform)),
+                &**kernel,
+            );
+            if gradient == Vec2::ZERO {
+                continue;
+            }
+            let particle_acc = gradient / mass_density.0;
+            acc.0 += particle_acc;
+
+            // Equal and opposite force on the body, acting where the particle is
+            let reaction = -particle_acc * mass.0;
+            force += reaction;
+            torque += (pos - center).perp_dot(reaction);
+        }
+        body.force = force;
+        body.torque = torque;
+    }
+}
+
+pub fn integrate_rigid_bodies(
+    mut bodies: Query<(&mut FluidRigidBody, &mut Transform)>,
+    domain: Res<SimDomain>,
+    params: Res<SimParameters>,
+) {
+    let dt = params.step_dt();
+    for (mut body, mut transform) in bodies.iter_mut() {
+        let linear = body.force * body.inverse_mass();
+        let angular = body.torque * body.inverse_inertia();
+        body.velocity += linear * dt;
+        body.angular_velocity += angular * dt;
+
+        transform.translation += (body.velocity * dt).extend(0.0);
+        transform.rotate_z(body.angular_velocity * dt);
+
+        collide_body_with_domain(&mut body, &mut transform, &domain);
+    }
+}
+
+/// Keeps the body inside the domain by checking points on its surface against the walls
+fn collide_body_with_domain(
+    body: &mut FluidRigidBody,
+    transform: &mut Transform,
+    domain: &SimDomain,
+) {
+    const SAMPLES: usize = 32;
+    let center = transform.translation.truncate();
+    let radius = body.shape.bounding_radius() * transform.scale.x.abs();
+    for i in 0..SAMPLES {
+        let dir = Vec2::from_angle(i as f32 / SAMPLES as f32 * std::f32::consts::TAU);
+        // Walk from the bounding circle onto the actual surface
+        let mut point = center + dir * radius;
+        for _ in 0..3 {
+            let contact = body.shape.probe(transform, point);
+            point -= contact.normal * contact.distance;
+        }
+
+        let Some(contact) = domain.shape.contact(point) else {
+            continue;
+        };
+        let correction = contact.point - point;
+        transform.translation += correction.extend(0.0);
+
+        let offset = point - center;
+        let normal_speed = body.point_velocity(offset).dot(contact.normal);
+        if normal_speed < 0.0 {
+            let restitution = domain.wall_properties(contact.wall).restitution;
+            let impulse = -contact.normal * normal_speed * (1.0 + restitution) * body.mass;
+            body.apply_impulse(impulse, offset);
+        }
+    }
+}
+
+/// Pushes a particle out of any rigid body it entered. The particle keeps its tangential
+/// velocity relative to the body and the body receives the opposite impulse.
+pub fn collide_rigid_bodies<'a>(
+    mut pos: Vec2,
+    mut vel: Vec2,
+    mass: f32,
+    bodies: impl Iterator<Item = (Mut<'a, FluidRigidBody>, &'a Transform)>,
+) -> (Vec2, Vec2) {
+    for (mut body, transform) in bodies {
+        let center = transform.translation.truncate();
+        if !body.shape.is_near(transform, pos, COLLISION_MARGIN) {
+            continue;
+        }
+        let contact = body.shape.probe(transform, pos);
+        if contact.distance >= COLLISION_MARGIN {
+            continue;
+        }
+        pos += contact.normal * (COLLISION_MARGIN - contact.distance);
+
+        let offset = pos - center;
+        let relative = vel - body.point_velocity(offset);
+        let normal_speed = relative.dot(contact.normal);
+        if normal_speed < 0.0 {
+            let new_vel = vel - contact.normal * normal_speed;
+            body.apply_impulse((vel - new_vel) * mass, offset);
+            vel = new_vel;
+        }
+    }
+    (pos, vel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::KernelKind;
+
+    #[test]
+    fn pressure_exchange_is_equal_and_opposite() {
+        let mut world = World::new();
+        world.insert_resource(Kernel::new(KernelKind::default(), 1.0));
+        world.insert_resource(EntityLookupChunk::new(1.0));
+        let mut params = SimParameters::default();
+        params.gravity = 0.0;
+        world.insert_resource(params);
+        let center = Vec2::new(0.3, -0.2);
+        world.spawn((
+            FluidRigidBody::from_density(
+                ObstacleShape::Box {
+                    half_extents: Vec2::new(1.0, 0.5),
+                },
+                1.0,
+            ),
+            Transform::from_translation(center.extend(0.0)),
+        ));
+        // An uneven ring of particles, so the body feels a net force and torque
+        for i in 0..24 {
+            let angle = i as f32 / 24.0 * std::f32::consts::TAU;
+            let pos = center + Vec2::new(1.2 * angle.cos(), 0.7 * angle.sin());
+            let chunk_position = world
+                .resource::<EntityLookupChunk>()
+                .chunk_position(pos.x, pos.y);
+            world.spawn((
+                Particle,
+                PredictedPos(pos),
+                Acceleration::default(),
+                LocalMassDensity(1.0 + i as f32 * 0.05),
+                Pressure(10.0 + i as f32),
+                Mass(1.0 + (i % 3) as f32),
+                chunk_position,
+            ));
+        }
+        let mut schedule = Schedule::default();
+        schedule.add_systems(couple_rigid_bodies);
+        schedule.run(&mut world);
+
+        let (fluid_force, fluid_torque) = world
+            .query::<(&Acceleration, &Mass, &PredictedPos)>()
+            .iter(&world)
+            .fold((Vec2::ZERO, 0.0), |(force, torque), (acc, mass, pos)| {
+                let particle_force = acc.0 * mass.0;
+                (
+                    force + particle_force,
+                    torque + (pos.0 - center).perp_dot(particle_force),
+                )
+            });
+        let body = world.query::<&FluidRigidBody>().single(&world);
+        assert!(body.force.length() > 1.0, "no force reached the body");
+        assert!((fluid_force + body.force).length() < 1e-3 * body.force.length());
+        assert!((fluid_torque + body.torque).abs() < 1e-3 * body.force.length());
+    }
+
+    #[test]
+    fn collision_conserves_momentum() {
+        let mut world = World::new();
+        world.spawn((
+            FluidRigidBody::from_density(ObstacleShape::Circle { radius: 1.0 }, 0.5),
+            Transform::default(),
+        ));
+        let mass = 0.8;
+        let vel = Vec2::new(-2.0, -0.5);
+        let body_momentum = |world: &mut World| {
+            let body = world.query::<&FluidRigidBody>().single(world);
+            body.velocity * body.mass
+        };
+        let before = vel * mass + body_momentum(&mut world);
+
+        let mut bodies = world.query::<(&mut FluidRigidBody, &Transform)>();
+        let (_, new_vel) =
+            collide_rigid_bodies(Vec2::new(0.95, 0.3), vel, mass, bodies.iter_mut(&mut world));
+        let after = new_vel * mass + body_momentum(&mut world);
+
+        assert!(new_vel != vel, "the particle did not hit the body");
+        assert!(
+            (before - after).length() < 1e-5,
+            "momentum {before} became {after}"
+        );
+    }
+
+    #[test]
+    fn massless_body_stays_finite() {
+        let mut body = FluidRigidBody::from_density(ObstacleShape::Circle { radius: 1.0 }, 0.0);
+        body.apply_impulse(Vec2::new(1.0, 2.0), Vec2::X);
+        assert_eq!(body.velocity, Vec2::ZERO);
+        assert_eq!(body.angular_velocity, 0.0);
+
+        let degenerate = ObstacleShape::ConvexPolygon {
+            vertices: vec![Vec2::ZERO, Vec2::X, Vec2::X * 2.0],
+        };
+        let mut body = FluidRigidBody::from_density(degenerate, 1.0);
+        body.apply_impulse(Vec2::new(1.0, 2.0), Vec2::Y);
+        assert!(body.velocity.is_finite() && body.angular_velocity.is_finite());
+    }
+}
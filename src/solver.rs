@@ -0,0 +1,48 @@
+use bevy::prelude::*;
+
+/// How incompressibility is enforced, chosen on `ParticlePlugin`. Every solver runs its own set of
+/// systems inside `SimStep`; the force systems and the spatial lookup are shared between them.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SolverKind {
+    /// Explicit pressure forces from the `EquationOfState` of every material
+    #[default]
+    Sph,
+    /// Position Based Fluids (Macklin and Müller 2013): density constraints on `PredictedPos`,
+    /// solved with a few Jacobi iterations per step
+    PositionBased,
+    /// Predictive-corrective incompressible SPH (Solenthaler and Pajarola 2009): pressures are
+    /// corrected iteratively until the predicted density error is below a tolerance
+    Pcisph,
+    /// Divergence-free SPH (Bender and Koschier 2015): a divergence-free solve on the velocities
+    /// followed by a constant density solve, which stays stable at large steps
+    Dfsph,
+}
+
+impl SolverKind {
+    pub const ALL: [SolverKind; 4] = [
+        SolverKind::Sph,
+        SolverKind::PositionBased,
+        SolverKind::Pcisph,
+        SolverKind::Dfsph,
+    ];
+}
+
+/// Run condition for the systems of a single solver
+pub fn solver_is(kind: SolverKind) -> impl Fn(Res<SolverKind>) -> bool + Clone {
+    move |solver: Res<SolverKind>| *solver == kind
+}
+
+/// Convergence of the last iterative pressure solves
+#[derive(Resource, Clone, Debug, Default)]
+pub struct SolverStats {
+    /// Iterations the solve of the last substep took
+    pub iterations: u32,
+    /// Mean relative density error after the last iteration
+    pub residual: f32,
+    /// Largest relative density error of any particle after the last iteration
+    pub max_error: f32,
+    /// Iterations the divergence-free solve of the last substep took, only DFSPH has one
+    pub divergence_iterations: u32,
+    /// Mean relative density change over a step left after the divergence-free solve
+    pub divergence_residual: f32,
+}
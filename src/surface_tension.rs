@@ -0,0 +1,182 @@
+use crate::chunk::EntityLookupChunk;
+use crate::domain::SimDomain;
+use crate::kernel::Kernel;
+use crate::material::{FluidMaterials, MaterialId, SolidMaterialId};
+use crate::obstacle::Obstacle;
+use crate::particle::{
+    Acceleration, LocalMassDensity, Mass, Particle, PredictedPos, SimParameters,
+};
+use crate::rigid_body::FluidRigidBody;
+use bevy::prelude::*;
+
+/// Scaled SPH color field gradient. It is large at the free surface and close to zero inside
+/// the fluid, which is what the curvature term of the surface tension works with.
+#[derive(Component, Default, Clone, Debug)]
+pub struct SurfaceNormal(pub Vec2);
+
+/// Cohesion spline from Akinci et al. 2013, scaled to 1 at `h / 2`. It repels at very short
+/// distances and attracts further out.
+pub fn cohesion_spline(distance: f32, h: f32) -> f32 {
+    if !(0.0..=h).contains(&distance) {
+        return 0.0;
+    }
+    let value = (h - distance).powi(3) * distance.powi(3);
+    let value = if distance * 2.0 > h {
+        value
+    } else {
+        2.0 * value - h.powi(6) / 64.0
+    };
+    value * 64.0 / h.powi(6)
+}
+
+/// Adhesion spline from Akinci et al. 2013, scaled to a maximum of 1 at `3h / 4`
+pub fn adhesion_spline(distance: f32, h: f32) -> f32 {
+    if distance * 2.0 <= h || distance > h {
+        return 0.0;
+    }
+    let inner = -4.0 * distance * distance / h + 6.0 * distance - 2.0 * h;
+    (inner / (0.25 * h)).max(0.0).powf(0.25)
+}
+
+/// Every phase has its own color field, so the normals also point out of the interfaces between
+/// materials and the curvature term keeps the phases apart.
+pub fn calc_surface_normals(
+    mut particles: Query<(&mut SurfaceNormal, &PredictedPos, &MaterialId), With<Particle>>,
+    read_particles: Query<(&PredictedPos, &LocalMassDensity, &Mass, &MaterialId), With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    kernel: Res<Kernel>,
+) {
+    for (mut normal, predicted, material) in particles.iter_mut() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        let mut result = Vec2::ZERO;
+        for entity in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            let (other_predicted, other_density, other_mass, other_material) = read_particles
+                .get(entity)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            if other_material != material {
+                continue;
+            }
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            result += kernel.gradient_at(diff) * other_mass.0 / other_density.0;
+        }
+        normal.0 = result * kernel.radius();
+    }
+}
+
+/// Cohesion and curvature forces between fluid particles (Akinci et al. 2013). Both terms are
+/// antisymmetric per pair, so surface tension never adds net momentum. Cohesion only acts within
+/// a phase, different materials do not pull each other in.
+pub fn calc_surface_tension(
+    mut particles: Query<
+        (
+            &mut Acceleration,
+            &PredictedPos,
+            &SurfaceNormal,
+            &Mass,
+            &MaterialId,
+        ),
+        With<Particle>,
+    >,
+    read_particles: Query<(&PredictedPos, &SurfaceNormal, &Mass, &MaterialId), With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    materials: Res<FluidMaterials>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    let h = kernel.radius();
+    for (mut acc, predicted, normal, mass, material) in particles.iter_mut() {
+        let tension = materials.surface_tension(*material, params.surface_tension);
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        let mut force = Vec2::ZERO;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            let (other_predicted, other_normal, other_mass, other_material) = read_particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= h || dist <= 0.000001 {
+                continue;
+            }
+            let pair_tension = (tension
+                + materials.surface_tension(*other_material, params.surface_tension))
+                * 0.5;
+            let cohesion = if other_material == material {
+                -diff / dist * mass.0 * other_mass.0 * cohesion_spline(dist, h)
+            } else {
+                Vec2::ZERO
+            };
+            let curvature = -(normal.0 - other_normal.0) * mass.0;
+            force += (cohesion + curvature) * pair_tension;
+        }
+        if force.is_finite() {
+            acc.0 += force / mass.0;
+        }
+    }
+}
+
+/// Attraction between fluid particles and the walls, obstacles and rigid bodies near them.
+/// Rigid bodies are pulled towards the fluid in return.
+pub fn calc_adhesion(
+    mut particles: Query<(&mut Acceleration, &PredictedPos, &Mass, &MaterialId), With<Particle>>,
+    obstacles: Query<(&Obstacle, &Transform, Option<&SolidMaterialId>), Without<Particle>>,
+    mut bodies: Query<
+        (&mut FluidRigidBody, &Transform, Option<&SolidMaterialId>),
+        Without<Particle>,
+    >,
+    domain: Res<SimDomain>,
+    materials: Res<FluidMaterials>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    let h = kernel.radius();
+    for (mut acc, predicted, mass, material) in particles.iter_mut() {
+        let pos = predicted.0;
+
+        if let Some(wall) = domain.shape.nearest_wall(pos) {
+            let solid = domain.wall_properties(wall.wall).solid_material;
+            let strength = materials.adhesion(*material, solid, params.adhesion);
+            let offset = wall.point - pos;
+            let dist = offset.length();
+            if strength != 0.0 && dist > 0.0 {
+                acc.0 += offset / dist * strength * adhesion_spline(dist, h);
+            }
+        }
+
+        for (obstacle, obstacle_transform, solid) in obstacles.iter() {
+            let solid = solid.copied().unwrap_or_default().0;
+            let strength = materials.adhesion(*material, solid, params.adhesion);
+            if strength == 0.0 || !obstacle.shape.is_near(obstacle_transform, pos, h) {
+                continue;
+            }
+            let contact = obstacle.shape.probe(obstacle_transform, pos);
+            acc.0 -= contact.normal * strength * adhesion_spline(contact.distance, h);
+        }
+
+        for (mut body, body_transform, solid) in bodies.iter_mut() {
+            let solid = solid.copied().unwrap_or_default().0;
+            let strength = materials.adhesion(*material, solid, params.adhesion);
+            if strength == 0.0 || !body.shape.is_near(body_transform, pos, h) {
+                continue;
+            }
+            let contact = body.shape.probe(body_transform, pos);
+            let particle_acc = -contact.normal * strength * adhesion_spline(contact.distance, h);
+            if particle_acc == Vec2::ZERO {
+                continue;
+            }
+            acc.0 += particle_acc;
+
+            let reaction = -particle_acc * mass.0;
+            let offset = pos - body_transform.translation.truncate();
+            body.force += reaction;
+            body.torque += offset.perp_dot(reaction);
+        }
+    }
+}
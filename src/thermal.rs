@@ -0,0 +1,195 @@
+use crate::chunk::EntityLookupChunk;
+use crate::kernel::Kernel;
+use crate::particle::{LocalMassDensity, Mass, Particle, PredictedPos, SimParameters};
+use bevy::prelude::*;
+
+/// Temperature new particles start with, and the default reference of the thermal expansion
+pub const AMBIENT_TEMPERATURE: f32 = 20.0;
+
+#[derive(Resource, Clone, Debug)]
+pub struct ThermalParameters {
+    /// Thermal diffusivity, how fast heat spreads between neighbors, in area per second
+    pub diffusivity: f32,
+    /// Volume expansion per degree above `reference`. Hot fluid takes more room, becomes lighter
+    /// and rises under gravity, which drives convection.
+    pub expansion: f32,
+    /// Temperature the materials have their rest density at
+    pub reference: f32,
+}
+
+impl Default for ThermalParameters {
+    fn default() -> Self {
+        Self {
+            diffusivity: 0.5,
+            expansion: 0.005,
+            reference: AMBIENT_TEMPERATURE,
+        }
+    }
+}
+
+impl ThermalParameters {
+    /// Factor the volume of a particle grows by at `temperature`, kept positive for very cold
+    /// particles
+    pub fn expansion_factor(&self, temperature: f32) -> f32 {
+        (1.0 + self.expansion * (temperature - self.reference)).max(0.1)
+    }
+}
+
+#[derive(Component, Clone, Debug)]
+pub struct Temperature(pub f32);
+
+impl Default for Temperature {
+    fn default() -> Self {
+        Self(AMBIENT_TEMPERATURE)
+    }
+}
+
+/// Heats or cools the particles within `radius`, pulling them towards `temperature`. A heat sink
+/// is a source with a low temperature.
+#[derive(Component, Clone, Debug)]
+#[require(Transform)]
+pub struct HeatSource {
+    pub radius: f32,
+    pub temperature: f32,
+    /// Fraction of the temperature difference removed per second
+    pub rate: f32,
+}
+
+pub fn apply_heat_sources(
+    sources: Query<(&HeatSource, &Transform), Without<Particle>>,
+    mut particles: Query<(&PredictedPos, &mut Temperature), With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    params: Res<SimParameters>,
+) {
+    let dt = params.step_dt();
+    for (source, transform) in sources.iter() {
+        let center = transform.translation.truncate();
+        let reach = Vec2::splat(source.radius);
+        let blend = 1.0 - (-source.rate * dt).exp();
+        for entity in entity_lookup_chunk.get_area_entities(center - reach, center + reach) {
+            let Ok((predicted, mut temperature)) = particles.get_mut(entity) else {
+                continue;
+            };
+            if predicted.0.distance(center) > source.radius {
+                continue;
+            }
+            temperature.0 += (source.temperature - temperature.0) * blend;
+        }
+    }
+}
+
+/// Heat equation `dT/dt = α ∇²T` with the SPH Laplacian of Cleary and Monaghan 1999, which only
+/// needs first kernel derivatives. Each pair uses the mean of both densities, so the heat one
+/// particle gains is exactly what its neighbor loses even across phases.
+pub fn calc_heat_diffusion(
+    mut particles: Query<
+        (
+            Entity,
+            &PredictedPos,
+            &LocalMassDensity,
+            &Mass,
+            &mut Temperature,
+        ),
+        With<Particle>,
+    >,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    thermal: Res<ThermalParameters>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    if thermal.diffusivity <= 0.0 {
+        return;
+    }
+    let dt = params.step_dt();
+    let h = kernel.radius();
+
+    let mut changes = Vec::new();
+    for (entity, predicted, density, _, temperature) in particles.iter() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        let mut laplacian = 0.0;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            if other == entity {
+                continue;
+            }
+            let (_, other_predicted, other_density, other_mass, other_temperature) = particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= h || dist <= 0.000001 {
+                continue;
+            }
+            let volume = other_mass.0 / ((density.0 + other_density.0) * 0.5);
+            laplacian += 2.0
+                * volume
+                * (temperature.0 - other_temperature.0)
+                * diff.dot(kernel.gradient_at(diff))
+                / (dist * dist + 0.01 * h * h);
+        }
+        let change = thermal.diffusivity * laplacian * dt;
+        if change.is_finite() {
+            changes.push((entity, change));
+        }
+    }
+    for (entity, change) in changes {
+        if let Ok((_, _, _, _, mut temperature)) = particles.get_mut(entity) {
+            temperature.0 += change;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::KernelKind;
+    use crate::particle::{calc_local_mass_density, NearDensity};
+
+    #[test]
+    fn diffusion_conserves_heat() {
+        let mut world = World::new();
+        world.insert_resource(Kernel::new(KernelKind::default(), 1.0));
+        world.insert_resource(EntityLookupChunk::new(1.0));
+        world.init_resource::<ThermalParameters>();
+        world.init_resource::<SimParameters>();
+
+        // A light hot particle next to a heavy cold one, so their densities differ
+        for (x, mass, temperature) in [(0.0, 1.0, 80.0), (0.4, 3.0, 20.0)] {
+            let pos = Vec2::new(x, 0.0);
+            let chunk_position = world
+                .resource::<EntityLookupChunk>()
+                .chunk_position(pos.x, pos.y);
+            world.spawn((
+                Particle,
+                PredictedPos(pos),
+                Mass(mass),
+                LocalMassDensity::default(),
+                NearDensity::default(),
+                Temperature(temperature),
+                chunk_position,
+            ));
+        }
+        let mut schedule = Schedule::default();
+        schedule.add_systems((calc_local_mass_density, calc_heat_diffusion).chain());
+
+        let heat = |world: &mut World| -> (f32, f32) {
+            let mut query = world.query::<(&Mass, &Temperature)>();
+            let total = query.iter(world).map(|(mass, t)| mass.0 * t.0).sum();
+            let spread = query.iter(world).map(|(_, t)| t.0).fold(0.0, f32::max)
+                - query.iter(world).map(|(_, t)| t.0).fold(f32::MAX, f32::min);
+            (total, spread)
+        };
+        let (initial, initial_spread) = heat(&mut world);
+        for _ in 0..50 {
+            schedule.run(&mut world);
+        }
+        let (total, spread) = heat(&mut world);
+
+        assert!(spread < initial_spread, "no heat flowed: spread {spread}");
+        assert!(
+            (total - initial).abs() < 1e-4 * initial,
+            "total heat changed from {initial} to {total}"
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use crate::chunk::EntityLookupChunk;
+use crate::kernel::Kernel;
+use crate::material::{FluidMaterials, MaterialId};
+use crate::particle::{
+    Acceleration, LocalMassDensity, Mass, Particle, PredictedPos, SimParameters, Velocity,
+};
+use bevy::prelude::*;
+
+/// Viscosity of the particle at its current shear rate, from the `Rheology` of its material
+#[derive(Component, Default, Clone, Debug)]
+pub struct EffectiveViscosity {
+    pub viscosity: f32,
+    /// `γ̇ = sqrt(2 D:D)` with the strain rate tensor `D` from the SPH velocity gradient
+    pub shear_rate: f32,
+}
+
+/// Estimates the velocity gradient `∇v_i = Σ V_j (v_j - v_i) ⊗ ∇W` and evaluates the rheology of
+/// every particle's material at the resulting shear rate
+pub fn calc_effective_viscosity(
+    mut particles: Query<
+        (
+            &mut EffectiveViscosity,
+            &PredictedPos,
+            &Velocity,
+            &MaterialId,
+        ),
+        With<Particle>,
+    >,
+    read_particles: Query<(&PredictedPos, &Velocity, &LocalMassDensity, &Mass), With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    materials: Res<FluidMaterials>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    for (mut effective, predicted, vel, material) in particles.iter_mut() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        let mut gradient = Mat2::ZERO;
+        for entity in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            let (other_predicted, other_vel, other_density, other_mass) = read_particles
+                .get(entity)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let volume = other_mass.0 / other_density.0;
+            let dv = (other_vel.0 - vel.0) * volume;
+            let w = kernel.gradient_at(diff);
+            gradient += Mat2::from_cols(dv * w.x, dv * w.y);
+        }
+        let strain = (gradient + gradient.transpose()) * 0.5;
+        let shear_rate =
+            (2.0 * (strain.x_axis.length_squared() + strain.y_axis.length_squared())).sqrt();
+
+        effective.shear_rate = if shear_rate.is_finite() {
+            shear_rate
+        } else {
+            0.0
+        };
+        effective.viscosity =
+            materials.effective_viscosity(*material, params.viscosity, effective.shear_rate);
+    }
+}
+
+/// Laplacian viscosity in the formulation of Morris et al., which only needs the first kernel
+/// derivative. Each pair uses the sum `μ_i + μ_j` of both viscosities, which is symmetric, so
+/// honey next to water still exchanges equal and opposite forces.
+pub fn calc_viscosity_force(
+    mut particles: Query<
+        (
+            &mut Acceleration,
+            &PredictedPos,
+            &Velocity,
+            &LocalMassDensity,
+            &EffectiveViscosity,
+        ),
+        With<Particle>,
+    >,
+    read_particles: Query<
+        (
+            &PredictedPos,
+            &Velocity,
+            &LocalMassDensity,
+            &Mass,
+            &EffectiveViscosity,
+        ),
+        With<Particle>,
+    >,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    kernel: Res<Kernel>,
+) {
+    // Keeps the denominator away from zero for overlapping particles
+    let eta_sq = 0.01 * kernel.radius() * kernel.radius();
+
+    for (mut acc, predicted, vel, density, effective) in particles.iter_mut() {
+        let pos = predicted.0;
+        let viscosity = effective.viscosity;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        let mut result = Vec2::ZERO;
+        for entity in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            let (other_predicted, other_vel, other_density, other_mass, other_effective) =
+                read_particles
+                    .get(entity)
+                    .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let pair_viscosity = viscosity + other_effective.viscosity;
+            // r · ∇W, negative, so the pair gets pulled towards a common velocity
+            let factor = other_mass.0 * pair_viscosity * kernel.gradient(dist) * dist
+                / (density.0 * other_density.0 * (dist * dist + eta_sq));
+            result += (vel.0 - other_vel.0) * factor;
+        }
+        if result.is_finite() {
+            acc.0 += result;
+        }
+    }
+}
+
+/// XSPH velocity smoothing: nudges every particle towards the kernel-weighted mean velocity of
+/// its neighbors. `SimParameters::xsph` is the blend per tick of `SimParameters::dt`; every
+/// substep applies its share of it, so the smoothing does not depend on the substep count.
+pub fn apply_xsph(
+    mut particles: Query<
+        (
+            Entity,
+            &PredictedPos,
+            &mut Velocity,
+            &LocalMassDensity,
+            &Mass,
+        ),
+        With<Particle>,
+    >,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    if params.xsph <= 0.0 {
+        return;
+    }
+    // n substeps with this blend remove as much of the difference as one with `xsph`
+    let tick_share = params.step_dt() / params.dt.max(0.0001);
+    let blend = 1.0 - (1.0 - params.xsph.min(1.0)).powf(tick_share);
+
+    let mut corrections = Vec::new();
+    for (entity, predicted, vel, density, _) in particles.iter() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+
+        let mut correction = Vec2::ZERO;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            if other == entity {
+                continue;
+            }
+            let (_, other_predicted, other_vel, other_density, other_mass) = particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let dist = pos.distance(other_predicted.0);
+            if dist >= kernel.radius() {
+                continue;
+            }
+            let mean_density = (density.0 + other_density.0) * 0.5;
+            correction += (other_vel.0 - vel.0) * other_mass.0 / mean_density * kernel.value(dist);
+        }
+        if correction.is_finite() {
+            corrections.push((entity, correction * blend));
+        }
+    }
+
+    for (entity, correction) in corrections {
+        if let Ok((_, _, mut vel, _, _)) = particles.get_mut(entity) {
+            vel.0 += correction;
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::chunk::EntityLookupChunk;
+use crate::kernel::Kernel;
+use crate::particle::{
+    Acceleration, LocalMassDensity, Mass, Particle, PredictedPos, SimParameters, Velocity,
+};
+use bevy::prelude::*;
+
+/// Curl of the velocity field at the particle. In 2D it is a scalar, positive for
+/// counter-clockwise rotation.
+#[derive(Component, Default, Clone, Debug)]
+pub struct Vorticity(pub f32);
+
+pub fn calc_vorticity(
+    particles: Query<(Entity, &PredictedPos, &Velocity, &LocalMassDensity, &Mass), With<Particle>>,
+    mut vorticities: Query<&mut Vorticity, With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    kernel: Res<Kernel>,
+) {
+    let mut curls = Vec::new();
+    for (entity, predicted, vel, _, _) in particles.iter() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+        let mut curl = 0.0;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            let (_, other_predicted, other_vel, other_density, other_mass) = particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let weight = other_mass.0 / other_density.0;
+            curl += weight * (other_vel.0 - vel.0).perp_dot(-kernel.gradient_at(diff));
+        }
+        curls.push((entity, curl));
+    }
+    for (entity, curl) in curls {
+        if let Ok(mut vorticity) = vorticities.get_mut(entity) {
+            vorticity.0 = if curl.is_finite() { curl } else { 0.0 };
+        }
+    }
+}
+
+/// Confinement acceleration `ε (N × ω)` of every particle, with `N` pointing towards stronger
+/// vorticity. Needs [`calc_vorticity`] to have run on the current velocities.
+pub fn vorticity_confinement(
+    particles: &Query<
+        (Entity, &PredictedPos, &LocalMassDensity, &Mass, &Vorticity),
+        With<Particle>,
+    >,
+    entity_lookup_chunk: &EntityLookupChunk,
+    kernel: &Kernel,
+    strength: f32,
+) -> Vec<(Entity, Vec2)> {
+    let mut forces = Vec::new();
+    for (entity, predicted, _, _, vorticity) in particles.iter() {
+        let pos = predicted.0;
+        let chunk_pos = entity_lookup_chunk.chunk_position(pos.x, pos.y);
+        let mut location = Vec2::ZERO;
+        for other in entity_lookup_chunk.get_neighborhood_entities(&chunk_pos) {
+            let (_, other_predicted, other_density, other_mass, other_vorticity) = particles
+                .get(other)
+                .expect("Particle Chunk registry failed; Expected particle to exist; didn't");
+            let diff = pos - other_predicted.0;
+            let dist = diff.length();
+            if dist >= kernel.radius() || dist <= 0.000001 {
+                continue;
+            }
+            let weight = other_mass.0 / other_density.0;
+            location += kernel.gradient_at(diff) * weight * other_vorticity.0.abs();
+        }
+        let Some(normal) = location.try_normalize() else {
+            continue;
+        };
+        let curl = vorticity.0;
+        let force = Vec2::new(normal.y * curl, -normal.x * curl) * strength;
+        if force.is_finite() {
+            forces.push((entity, force));
+        }
+    }
+    forces
+}
+
+/// Puts back the small vortices numerical dissipation smears out
+pub fn calc_vorticity_confinement(
+    particles: Query<(Entity, &PredictedPos, &LocalMassDensity, &Mass, &Vorticity), With<Particle>>,
+    mut accelerations: Query<&mut Acceleration, With<Particle>>,
+    entity_lookup_chunk: Res<EntityLookupChunk>,
+    params: Res<SimParameters>,
+    kernel: Res<Kernel>,
+) {
+    if params.vorticity_confinement <= 0.0 {
+        return;
+    }
+    let forces = vorticity_confinement(
+        &particles,
+        &entity_lookup_chunk,
+        &kernel,
+        params.vorticity_confinement,
+    );
+    for (entity, force) in forces {
+        if let Ok(mut acc) = accelerations.get_mut(entity) {
+            acc.0 += force;
+        }
+    }
+}